@@ -1,72 +1,73 @@
 use std::sync::{atomic::AtomicBool, Arc};
 
+use crate::testconfig::TestConfig;
 use crate::{
-    influx::launch_influx_service, prometheus::prometheus_thread, twxquery::launch_twxquery_service,
+    fileexport::FileSink,
+    graphite::GraphiteSink,
+    influx::InfluxWriter,
+    otlp::OtlpWriter,
+    prometheus::PrometheusSink,
+    reload::{self, ConfigReceiver},
+    remotewrite::RemoteWriter,
+    selfmetrics,
+    sink::{run_dispatcher, Dispatcher, Sink, SinkSpec},
+    statsd::StatsdSink,
+    twxquery::launch_twxquery_service,
 };
-use crate::{spec::WriteSpec, testconfig::TestConfig};
-use tokio::sync::mpsc::{channel, Sender};
+use serde::Serialize;
+use tokio::sync::{mpsc::channel, watch};
 
 pub async fn run_app(
+    config_file: String,
     tc: TestConfig,
     running: Arc<AtomicBool>,
-    main_query_sleeping: Arc<AtomicBool>,
-    cxs_refresh_sleeping: Arc<AtomicBool>,
-    jmx_refresh_sleeping: Arc<AtomicBool>,
 ) -> anyhow::Result<()> {
     if let Some(ref owner) = tc.owner {
         log::info!("test owner:{:?}", owner);
     }
 
+    selfmetrics::init();
     let (sender, receiver) = channel(1000);
 
-    let prometheus_sender: Option<Sender<Vec<WriteSpec>>> =
-        if let Some(ref prometheus_config) = tc.export_to_prometheus {
-            let etp = prometheus_config.clone();
+    // the services follow the configuration file, a new valid configuration is applied live.
+    let tc = Arc::new(tc);
+    let (config_sender, config) = watch::channel(tc.clone());
 
-            let enabled = etp.enabled;
-            if enabled {
-                let (prom_sender, prom_receiver) = channel(1000);
-                tokio::spawn(async move { prometheus_thread(etp, prom_receiver).await });
-                Some(prom_sender)
-            } else {
-                None
-            }
-        } else {
-            None
-        };
-    // launch influx service to store data first.
-    let export_to_influxdb = tc.export_to_influxdb.clone();
-    let export_to_file = tc.export_to_file.clone();
-    let export_to_influxdb_task = tokio::spawn(async move {
-        match launch_influx_service(
-            &export_to_influxdb,
-            receiver,
-            export_to_file,
-            prometheus_sender,
-        )
-        .await
-        {
-            Ok(_) => {
-                log::info!("influxdb service finished smoothly");
-            }
-            Err(e) => {
-                log::error!("influxdb service error:{:?}", e);
+    // every export gets its own copy of the batches.
+    let mut dispatcher = Dispatcher::new();
+    for spec in build_sinks(&tc, &config) {
+        dispatcher.add(spec);
+    }
+    if dispatcher.is_empty() {
+        log::warn!("no export is enabled, the metrics will be collected but not stored.");
+    }
+
+    let reload_task = tokio::spawn(reload::watch_config(config_file, config_sender));
+
+    let (sink_sender, sink_receiver) = channel(1);
+    let mut sink_config = config.clone();
+    let sink_update_task = tokio::spawn(async move {
+        while sink_config.changed().await.is_ok() {
+            let tc = sink_config.borrow_and_update().clone();
+            if sink_sender
+                .send(build_sinks(&tc, &sink_config))
+                .await
+                .is_err()
+            {
+                break;
             }
         }
     });
+    let dispatcher_task = tokio::spawn(run_dispatcher(dispatcher, receiver, sink_receiver));
+
+    let internal_metrics_task = tokio::spawn(selfmetrics::export_internal_metrics(
+        sender.clone(),
+        config.clone(),
+    ));
 
     let query_running = running.clone();
     let twx_query_task = tokio::spawn(async move {
-        match launch_twxquery_service(
-            tc.clone(),
-            sender,
-            query_running,
-            main_query_sleeping,
-            cxs_refresh_sleeping,
-            jmx_refresh_sleeping,
-        )
-        .await
-        {
+        match launch_twxquery_service(config, sender, query_running).await {
             Ok(_) => {
                 log::info!("twxquery service finished.");
             }
@@ -77,7 +78,99 @@ pub async fn run_app(
     });
 
     let _ = twx_query_task.await;
-    let _ = export_to_influxdb_task.await;
+    reload_task.abort();
+    sink_update_task.abort();
+    // its sender would keep the dispatcher open.
+    internal_metrics_task.abort();
+    let _ = dispatcher_task.await;
 
     Ok(())
 }
+
+/// Creates one sink per enabled export, the sinks that follow the configuration get `config`.
+pub fn build_sinks(tc: &TestConfig, config: &ConfigReceiver) -> Vec<SinkSpec> {
+    let mut sinks = vec![];
+    if tc.export_to_influxdb.enabled {
+        let config = tc.export_to_influxdb.clone();
+        sinks.push(SinkSpec {
+            name: "influxdb".to_string(),
+            settings: settings_of(&config),
+            factory: Box::new(move || {
+                let config = config.clone();
+                Box::pin(async move { Ok(Box::new(InfluxWriter::new(&config)?) as Box<dyn Sink>) })
+            }),
+        });
+    }
+    if let Some(config) = tc.export_to_file.clone().filter(|c| c.enabled) {
+        sinks.push(SinkSpec {
+            name: "file".to_string(),
+            settings: settings_of(&config),
+            factory: Box::new(move || {
+                let config = config.clone();
+                Box::pin(async move { Ok(Box::new(FileSink::open(config)?) as Box<dyn Sink>) })
+            }),
+        });
+    }
+    if let Some(etp) = tc.export_to_prometheus.clone().filter(|c| c.enabled) {
+        // the stale window follows the intervals of the sources, they aren't in its settings.
+        let config = config.clone();
+        sinks.push(SinkSpec {
+            name: "prometheus".to_string(),
+            settings: settings_of(&etp),
+            factory: Box::new(move || {
+                let (etp, config) = (etp.clone(), config.clone());
+                Box::pin(async move {
+                    Ok(Box::new(PrometheusSink::new(etp, config).await?) as Box<dyn Sink>)
+                })
+            }),
+        });
+    }
+    if let Some(config) = tc.export_to_remote_write.clone().filter(|c| c.enabled) {
+        sinks.push(SinkSpec {
+            name: "remote_write".to_string(),
+            settings: settings_of(&config),
+            factory: Box::new(move || {
+                let config = config.clone();
+                Box::pin(async move { Ok(Box::new(RemoteWriter::new(&config)?) as Box<dyn Sink>) })
+            }),
+        });
+    }
+    if let Some(config) = tc.export_to_otlp.clone().filter(|c| c.enabled) {
+        sinks.push(SinkSpec {
+            name: "otlp".to_string(),
+            settings: settings_of(&config),
+            factory: Box::new(move || {
+                let config = config.clone();
+                Box::pin(async move { Ok(Box::new(OtlpWriter::new(&config)?) as Box<dyn Sink>) })
+            }),
+        });
+    }
+    if let Some(config) = tc.export_to_graphite.clone().filter(|c| c.enabled) {
+        sinks.push(SinkSpec {
+            name: "graphite".to_string(),
+            settings: settings_of(&config),
+            factory: Box::new(move || {
+                let config = config.clone();
+                Box::pin(async move { Ok(Box::new(GraphiteSink::new(&config)?) as Box<dyn Sink>) })
+            }),
+        });
+    }
+    if let Some(config) = tc.export_to_statsd.clone().filter(|c| c.enabled) {
+        sinks.push(SinkSpec {
+            name: "statsd".to_string(),
+            settings: settings_of(&config),
+            factory: Box::new(move || {
+                let config = config.clone();
+                Box::pin(
+                    async move { Ok(Box::new(StatsdSink::new(&config).await?) as Box<dyn Sink>) },
+                )
+            }),
+        });
+    }
+    sinks
+}
+
+// a sink is created again when the serialized configuration of its export changes.
+fn settings_of<T: Serialize>(config: &T) -> String {
+    serde_yaml::to_string(config).unwrap_or_default()
+}
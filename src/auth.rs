@@ -0,0 +1,215 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::testconfig::{ServerAuth, ThingworxServer};
+use crate::twxquery::construct_headers;
+use anyhow::Context;
+use lazy_static::lazy_static;
+use reqwest::{
+    header::{HeaderMap, HeaderValue, ACCEPT, AUTHORIZATION, CONTENT_TYPE},
+    Client,
+};
+use serde::Deserialize;
+use tokio::time::Instant;
+
+// used when the token response has no expires_in.
+const DEFAULT_EXPIRES_IN: u64 = 3600;
+const TOKEN_TIMEOUT: Duration = Duration::from_secs(20);
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: Option<u64>,
+}
+
+#[derive(Debug)]
+struct CachedToken {
+    auth: ServerAuth,
+    token: String,
+    // when the token is requested again, `refresh_before` seconds before its expiry.
+    refresh_at: Instant,
+}
+
+type TokenSlot = Arc<tokio::sync::Mutex<Option<CachedToken>>>;
+
+lazy_static! {
+    // one token per ThingWorx server name, the queries of a server wait for the same request.
+    static ref TOKENS: Mutex<HashMap<String, TokenSlot>> = Mutex::new(HashMap::new());
+    // the token requests don't use the client of the server, its certificate checks and
+    // the address of its tls.server_name don't apply to token_url, which gets the secret.
+    static ref TOKEN_CLIENT: Client = Client::builder()
+        .timeout(TOKEN_TIMEOUT)
+        .build()
+        .expect("failed to create the http client of the token requests");
+}
+
+fn slot(server_name: &str) -> TokenSlot {
+    TOKENS
+        .lock()
+        .expect("Lock poisoned.")
+        .entry(server_name.to_string())
+        .or_default()
+        .clone()
+}
+
+/// The request headers of the server: its app key, its static bearer token, or a token
+/// of its `auth` block, requested when the cached one is about to expire. The token request
+/// always verifies the certificate of `token_url`.
+pub async fn headers_for(server: &ThingworxServer) -> anyhow::Result<HeaderMap> {
+    let auth = match server.auth {
+        Some(ref auth) => auth,
+        None => return Ok(construct_headers(&server.app_key)),
+    };
+
+    let slot = slot(&server.name);
+    let mut cached = slot.lock().await;
+    let valid = cached
+        .as_ref()
+        .filter(|cached| cached.auth == *auth && Instant::now() < cached.refresh_at);
+    let token = match valid {
+        Some(cached) => cached.token.clone(),
+        None => {
+            let response = request_token(auth)
+                .await
+                .with_context(|| format!("failed to get a token for server:{}", server.name))?;
+            let expires_in = response.expires_in.unwrap_or(DEFAULT_EXPIRES_IN);
+            log::info!(
+                "token of server:{} refreshed, it expires in {}s",
+                server.name,
+                expires_in
+            );
+            let refresh_at = Instant::now()
+                + Duration::from_secs(expires_in.saturating_sub(auth.refresh_before));
+            *cached = Some(CachedToken {
+                auth: auth.clone(),
+                token: response.access_token.clone(),
+                refresh_at,
+            });
+            response.access_token
+        }
+    };
+
+    let mut headers = HeaderMap::new();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    headers.insert(ACCEPT, HeaderValue::from_static("application/json"));
+    headers.insert(
+        AUTHORIZATION,
+        HeaderValue::from_str(&format!("Bearer {}", token))?,
+    );
+    Ok(headers)
+}
+
+/// Drops the cached token of the server after a 401 to the request sent with these headers,
+/// the next query requests a new one. A token already replaced by another query is kept.
+/// Returns false when the server doesn't use an `auth` block.
+pub async fn invalidate(server: &ThingworxServer, rejected: &HeaderMap) -> bool {
+    if server.auth.is_none() {
+        return false;
+    }
+    let rejected = rejected
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok());
+    let slot = slot(&server.name);
+    let mut cached = slot.lock().await;
+    let is_rejected = match (cached.as_ref(), rejected) {
+        (Some(cached), Some(rejected)) => rejected == format!("Bearer {}", cached.token),
+        _ => false,
+    };
+    if is_rejected {
+        log::warn!(
+            "token of server:{} rejected, requesting a new one",
+            server.name
+        );
+        cached.take();
+    }
+    true
+}
+
+async fn request_token(auth: &ServerAuth) -> anyhow::Result<TokenResponse> {
+    let mut form = vec![
+        ("grant_type", "client_credentials"),
+        ("client_id", auth.client_id.as_str()),
+        ("client_secret", auth.client_secret.as_str()),
+    ];
+    if let Some(ref scope) = auth.scope {
+        form.push(("scope", scope.as_str()));
+    }
+    let res = TOKEN_CLIENT
+        .post(&auth.token_url)
+        .form(&form)
+        .send()
+        .await?;
+    let status = res.status();
+    if !status.is_success() {
+        let body = res.text().await.unwrap_or_default();
+        return Err(anyhow::anyhow!(
+            "token request to {} failed with {}:{}",
+            auth.token_url,
+            status,
+            body
+        ));
+    }
+    Ok(res.json::<TokenResponse>().await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    #[tokio::test]
+    async fn test_token_cached_and_invalidated() {
+        // a token endpoint that counts its requests.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buffer = [0u8; 4096];
+                let read = stream.read(&mut buffer).await.unwrap();
+                assert!(String::from_utf8_lossy(&buffer[..read])
+                    .contains("grant_type=client_credentials&client_id=tsample"));
+                let count = counter.fetch_add(1, Ordering::SeqCst) + 1;
+                let body = format!(r#"{{"access_token":"token{}","expires_in":3600}}"#, count);
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        let server: ThingworxServer = serde_yaml::from_str(&format!(
+            "{{name: test_auth, host: localhost, port: 8080, subsystems: [], \
+             auth: {{token_url: 'http://{}/token', client_id: tsample, client_secret: secret}}}}",
+            address
+        ))
+        .unwrap();
+        assert!(server.app_key.is_empty());
+        let bearer = |headers: HeaderMap| headers[AUTHORIZATION].to_str().unwrap().to_string();
+
+        assert_eq!(bearer(headers_for(&server).await.unwrap()), "Bearer token1");
+        assert_eq!(bearer(headers_for(&server).await.unwrap()), "Bearer token1");
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+        let rejected = headers_for(&server).await.unwrap();
+        assert!(invalidate(&server, &rejected).await);
+        assert_eq!(bearer(headers_for(&server).await.unwrap()), "Bearer token2");
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+        // another query rejected with token1 keeps token2.
+        assert!(invalidate(&server, &rejected).await);
+        assert_eq!(bearer(headers_for(&server).await.unwrap()), "Bearer token2");
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,286 @@
+use std::{collections::HashMap, future::Future, sync::Mutex, time::Duration};
+
+use crate::auth::{headers_for, invalidate};
+use crate::selfmetrics::{Scrape, CIRCUIT_STATE, CIRCUIT_TRANSITIONS, SCRAPE_RETRIES};
+use crate::spec::WriteSpec;
+use crate::testconfig::{CircuitBreakerConfig, ThingworxServer};
+use influxdb::{Timestamp, Type};
+use lazy_static::lazy_static;
+use reqwest::header::HeaderMap;
+use tokio::{sync::mpsc::Sender, time::Instant};
+
+// one point per state change of the circuit breaker of a server.
+pub const CIRCUIT_MEASUREMENT: &str = "tsample_circuit_breaker";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Closed,
+    // the queries are skipped until the probe.
+    Open { until: Instant },
+    // one query probes the server, another one is let through if it doesn't finish in time.
+    HalfOpen { until: Instant },
+}
+
+impl State {
+    fn name(&self) -> &'static str {
+        match self {
+            State::Closed => "closed",
+            State::Open { .. } => "open",
+            State::HalfOpen { .. } => "half_open",
+        }
+    }
+
+    fn value(&self) -> i64 {
+        match self {
+            State::Closed => 0,
+            State::Open { .. } => 1,
+            State::HalfOpen { .. } => 2,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Breaker {
+    state: State,
+    failures: u32,
+}
+
+lazy_static! {
+    // one breaker per ThingWorx server name.
+    static ref BREAKERS: Mutex<HashMap<String, Breaker>> = Mutex::new(HashMap::new());
+}
+
+/// Whether a query can be sent to the server, with the event of the state change if any.
+fn admit(
+    server_name: &str,
+    config: &CircuitBreakerConfig,
+    now: Instant,
+) -> (bool, Option<WriteSpec>) {
+    let mut breakers = BREAKERS.lock().expect("Lock poisoned.");
+    let breaker = match breakers.get_mut(server_name) {
+        Some(breaker) => breaker,
+        None => return (true, None),
+    };
+    match breaker.state {
+        State::Closed => (true, None),
+        State::Open { until } | State::HalfOpen { until } if now >= until => {
+            let probe = State::HalfOpen {
+                until: now + Duration::from_secs(config.probe_interval.max(1)),
+            };
+            (true, Some(transition(server_name, breaker, probe, config)))
+        }
+        _ => (false, None),
+    }
+}
+
+/// Counts the result of a query, the server is unreachable when it failed with a transient error.
+fn report(
+    server_name: &str,
+    config: &CircuitBreakerConfig,
+    reachable: bool,
+    now: Instant,
+) -> Option<WriteSpec> {
+    let mut breakers = BREAKERS.lock().expect("Lock poisoned.");
+    let breaker = breakers.entry(server_name.to_string()).or_insert(Breaker {
+        state: State::Closed,
+        failures: 0,
+    });
+    if reachable {
+        breaker.failures = 0;
+        if breaker.state == State::Closed {
+            return None;
+        }
+        return Some(transition(server_name, breaker, State::Closed, config));
+    }
+
+    breaker.failures += 1;
+    let open = match breaker.state {
+        State::HalfOpen { .. } => true,
+        State::Closed => breaker.failures >= config.failure_threshold.max(1),
+        State::Open { .. } => false,
+    };
+    if !open {
+        return None;
+    }
+    let state = State::Open {
+        until: now + Duration::from_secs(config.probe_interval.max(1)),
+    };
+    Some(transition(server_name, breaker, state, config))
+}
+
+fn transition(
+    server_name: &str,
+    breaker: &mut Breaker,
+    state: State,
+    config: &CircuitBreakerConfig,
+) -> WriteSpec {
+    match state {
+        State::Open { .. } => log::warn!(
+            "circuit breaker of server:{} opened after {} consecutive failures, probing again in {}s",
+            server_name,
+            breaker.failures,
+            config.probe_interval
+        ),
+        State::HalfOpen { .. } => {
+            log::info!("circuit breaker of server:{} is half open, probing", server_name)
+        }
+        State::Closed => log::info!("circuit breaker of server:{} closed", server_name),
+    }
+    breaker.state = state;
+    CIRCUIT_STATE
+        .with_label_values(&[server_name])
+        .set(state.value());
+    CIRCUIT_TRANSITIONS
+        .with_label_values(&[server_name, state.name()])
+        .inc();
+
+    let timestamp = Timestamp::Milliseconds(chrono::Utc::now().timestamp_millis() as u128);
+    WriteSpec::new(timestamp, CIRCUIT_MEASUREMENT)
+        .add_tag("Platform", Type::Text(server_name.to_string()))
+        .add_field("state", Type::Text(state.name().to_string()))
+        .add_field(
+            "consecutive_failures",
+            Type::SignedInteger(breaker.failures as i64),
+        )
+}
+
+/// Runs a query of the server and sends its metrics with the status point of the target.
+/// The metric queries only read, so a query failed with a transient error is sent again up to
+/// `max_retries` times. Nothing is sent to the server while its circuit breaker is open.
+/// The query gets the request headers, a query rejected with 401 is sent once more with a
+/// new token when the server has an `auth` block.
+pub async fn run_query<F, Fut>(
+    server: &ThingworxServer,
+    kind: &'static str,
+    target: &str,
+    sender: &Sender<Vec<WriteSpec>>,
+    mut query: F,
+) where
+    F: FnMut(Scrape, HeaderMap) -> Fut,
+    Fut: Future<Output = (Scrape, anyhow::Result<Vec<WriteSpec>>)>,
+{
+    let breaker = server.circuit_breaker.as_ref();
+    let mut events = vec![];
+    if let Some(config) = breaker {
+        let (allowed, event) = admit(&server.name, config, Instant::now());
+        events.extend(event);
+        if !allowed {
+            log::debug!("{} query of {} skipped, the circuit is open", kind, target);
+            let mut scrape = Scrape::start(&server.name, kind, target);
+            scrape.circuit_open();
+            events.push(scrape.finish());
+            let _ = sender.send(events).await;
+            return;
+        }
+    }
+
+    let mut backoff = Duration::from_millis(server.retry_backoff.max(1));
+    let mut retry = 0;
+    let mut reauthorized = false;
+    let (scrape, result) = loop {
+        let mut scrape = Scrape::start(&server.name, kind, target);
+        let (mut scrape, result, sent) = match headers_for(server).await {
+            Ok(headers) => {
+                let (scrape, result) = query(scrape, headers.clone()).await;
+                (scrape, result, headers)
+            }
+            Err(e) => {
+                scrape.auth_failed();
+                (scrape, Err(e), HeaderMap::new())
+            }
+        };
+        if let Err(ref e) = result {
+            scrape.error(e);
+        }
+        if scrape.http_status() == Some(401) && !reauthorized && invalidate(server, &sent).await {
+            reauthorized = true;
+            continue;
+        }
+        if !scrape.is_transient() || retry >= server.max_retries {
+            break (scrape, result);
+        }
+        retry += 1;
+        log::warn!(
+            "{} query of {} failed with {}, retry {}/{} in {:?}",
+            kind,
+            target,
+            scrape.failure().unwrap_or("error"),
+            retry,
+            server.max_retries,
+            backoff
+        );
+        SCRAPE_RETRIES
+            .with_label_values(&[&server.name, kind, target])
+            .inc();
+        // the failed attempt is counted when it's dropped.
+        drop(scrape);
+        tokio::time::sleep(backoff).await;
+        backoff *= 2;
+    };
+
+    if let Some(config) = breaker {
+        events.extend(report(
+            &server.name,
+            config,
+            !scrape.is_transient(),
+            Instant::now(),
+        ));
+    }
+    let mut specs = match result {
+        Ok(specs) => {
+            log::debug!("{} query of {} has {} metrics", kind, target, specs.len());
+            specs
+        }
+        Err(e) => {
+            log::error!("{} query of {} error:{:?}", kind, target, e);
+            vec![]
+        }
+    };
+    specs.push(scrape.finish());
+    specs.append(&mut events);
+    let _ = sender.send(specs).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_breaker_transitions() {
+        let config = CircuitBreakerConfig {
+            failure_threshold: 2,
+            probe_interval: 10,
+        };
+        let name = "test_breaker";
+        let state = || BREAKERS.lock().unwrap().get(name).unwrap().state.name();
+        let start = Instant::now();
+
+        assert!(report(name, &config, false, start).is_none());
+        assert!(admit(name, &config, start).0);
+        let event = report(name, &config, false, start).unwrap();
+        assert_eq!(event.measurement, CIRCUIT_MEASUREMENT);
+        assert_eq!(event.fields[0].1.to_string(), "open");
+        assert_eq!(CIRCUIT_STATE.with_label_values(&[name]).get(), 1);
+        assert!(!admit(name, &config, start + Duration::from_secs(5)).0);
+
+        // the probe fails, the circuit opens again.
+        let probe = start + Duration::from_secs(10);
+        let (allowed, event) = admit(name, &config, probe);
+        assert!(allowed);
+        assert_eq!(event.unwrap().fields[0].1.to_string(), "half_open");
+        assert!(!admit(name, &config, probe).0);
+        report(name, &config, false, probe).unwrap();
+        assert_eq!(state(), "open");
+
+        // the next probe succeeds.
+        let probe = probe + Duration::from_secs(10);
+        assert!(admit(name, &config, probe).0);
+        let event = report(name, &config, true, probe).unwrap();
+        assert_eq!(event.fields[0].1.to_string(), "closed");
+        assert_eq!(state(), "closed");
+        assert_eq!(
+            CIRCUIT_TRANSITIONS.with_label_values(&[name, "open"]).get(),
+            2
+        );
+    }
+}
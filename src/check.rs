@@ -0,0 +1,773 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::include::{self, ConfigFile};
+use crate::scheduler::source_intervals;
+use crate::secrets;
+use crate::testconfig::TestConfig;
+use serde_yaml::Value;
+use yaml_rust::{
+    parser::{Event, MarkedEventReceiver, Parser},
+    scanner::Marker,
+};
+
+// the subsystems of a ThingWorx server, another name is reported as a warning.
+const KNOWN_SUBSYSTEMS: &[&str] = &[
+    "ValueStreamProcessingSubsystem",
+    "StreamProcessingSubsystem",
+    "EventProcessingSubsystem",
+    "DataTableProcessingSubsystem",
+    "PlatformSubsystem",
+    "WSCommunicationsSubsystem",
+    "WSExecutionProcessingSubsystem",
+    "TunnelSubsystem",
+    "AlertProcessingSubsystem",
+    "FederationSubsystem",
+];
+const PRECISIONS: &[&str] = &["ns", "us", "ms", "s"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// A problem of the configuration, at its YAML path like `thingworx_servers[1].port`.
+#[derive(Debug)]
+pub struct Problem {
+    pub severity: Severity,
+    pub path: String,
+    pub message: String,
+}
+
+#[derive(Default)]
+struct Problems(Vec<Problem>);
+
+impl Problems {
+    fn error(&mut self, path: impl Into<String>, message: impl Into<String>) {
+        self.push(Severity::Error, path.into(), message.into());
+    }
+
+    fn warning(&mut self, path: impl Into<String>, message: impl Into<String>) {
+        self.push(Severity::Warning, path.into(), message.into());
+    }
+
+    fn push(&mut self, severity: Severity, path: String, message: String) {
+        self.0.push(Problem {
+            severity,
+            path,
+            message,
+        });
+    }
+
+    fn port(&mut self, path: String, port: u16) {
+        if port == 0 {
+            self.error(path, "port 0 is not valid");
+        }
+    }
+
+    fn protocol(&mut self, path: String, protocol: &str) {
+        if protocol != "http" && protocol != "https" {
+            self.error(
+                path,
+                format!(
+                    "unknown protocol \"{}\", it should be http or https",
+                    protocol
+                ),
+            );
+        }
+    }
+
+    fn url(&mut self, path: String, url: &str) {
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+            self.error(path, format!("\"{}\" is not a http or https URL", url));
+        }
+    }
+
+    fn pair(&mut self, path: &str, first: (&str, bool), second: (&str, bool)) {
+        if first.1 != second.1 {
+            self.error(
+                format!("{}.{}", path, if first.1 { first.0 } else { second.0 }),
+                format!("{} and {} must be set together", first.0, second.0),
+            );
+        }
+    }
+
+    fn buckets(&mut self, path: String, buckets: &[f64]) {
+        if buckets.iter().any(|bucket| !bucket.is_finite()) {
+            self.error(path, "the bucket bounds must be finite numbers");
+        } else if buckets.windows(2).any(|pair| pair[0] >= pair[1]) {
+            self.error(path, "the bucket bounds must be in increasing order");
+        }
+    }
+}
+
+impl TestConfig {
+    /// The semantic problems of the configuration, like duplicate names or invalid ports.
+    pub fn validate(&self) -> Vec<Problem> {
+        let mut problems = Problems::default();
+
+        if self.thingworx_servers.is_empty() {
+            problems.warning("thingworx_servers", "no ThingWorx server is configured");
+        }
+        let mut names = HashSet::new();
+        for (index, server) in self.thingworx_servers.iter().enumerate() {
+            let path = format!("thingworx_servers[{}]", index);
+            if server.name.is_empty() {
+                problems.error(format!("{}.name", path), "the server name is empty");
+            } else if !names.insert(server.name.as_str()) {
+                problems.error(
+                    format!("{}.name", path),
+                    format!(
+                        "duplicate server name \"{}\", the servers would share one http client, rate limit and circuit breaker",
+                        server.name
+                    ),
+                );
+            }
+            problems.protocol(format!("{}.protocol", path), &server.protocol);
+            problems.port(format!("{}.port", path), server.port);
+            match server.auth {
+                Some(ref auth) => {
+                    problems.url(format!("{}.auth.token_url", path), &auth.token_url);
+                    if auth.client_id.is_empty() {
+                        problems.error(format!("{}.auth.client_id", path), "client_id is empty");
+                    }
+                }
+                None if server.app_key.is_empty() => {
+                    problems.error(
+                        format!("{}.app_key", path),
+                        "app_key is empty and there is no auth block",
+                    );
+                }
+                None => {}
+            }
+            if let Some(ref tls) = server.tls {
+                problems.pair(
+                    &format!("{}.tls", path),
+                    ("client_cert", tls.client_cert.is_some()),
+                    ("client_key", tls.client_key.is_some()),
+                );
+                if !tls.verify && server.protocol == "https" {
+                    problems.warning(
+                        format!("{}.tls.verify", path),
+                        "the certificate of the https server is not verified",
+                    );
+                }
+            }
+            if server.max_concurrent_requests == Some(0) {
+                problems.error(
+                    format!("{}.max_concurrent_requests", path),
+                    "max_concurrent_requests must be at least 1",
+                );
+            }
+            if server
+                .max_requests_per_second
+                .map_or(false, |rate| !rate.is_finite() || rate <= 0.0)
+            {
+                problems.error(
+                    format!("{}.max_requests_per_second", path),
+                    "max_requests_per_second must be a positive number",
+                );
+            }
+            if server
+                .circuit_breaker
+                .as_ref()
+                .map_or(false, |breaker| breaker.failure_threshold == 0)
+            {
+                problems.error(
+                    format!("{}.circuit_breaker.failure_threshold", path),
+                    "failure_threshold must be at least 1",
+                );
+            }
+            let jitter = Duration::from_secs(server.scrap_jitter.unwrap_or(self.scrap_jitter));
+            let shortest = source_intervals(self, server).into_iter().min();
+            if !jitter.is_zero() && matches!(shortest, Some(interval) if jitter >= interval) {
+                let jitter_path = match server.scrap_jitter {
+                    Some(_) => format!("{}.scrap_jitter", path),
+                    None => "scrap_jitter".to_string(),
+                };
+                problems.error(
+                    jitter_path,
+                    format!(
+                        "the scrap_jitter of {} must be shorter than its shortest interval, {}s",
+                        server.name,
+                        shortest.unwrap_or_default().as_secs()
+                    ),
+                );
+            }
+
+            let nothing_to_query = server.subsystems.is_empty()
+                && server.connection_servers.is_none()
+                && server
+                    .jmx_metrics
+                    .as_ref()
+                    .map_or(true, |jmx| jmx.is_empty())
+                && server
+                    .arbitrary_metrics
+                    .as_ref()
+                    .map_or(true, |am| am.is_empty());
+            if nothing_to_query {
+                problems.warning(
+                    format!("{}.subsystems", path),
+                    "nothing to query, the server has no subsystems and there are no default_subsystems",
+                );
+            }
+            let mut subsystems = HashSet::new();
+            for (sub_index, subsystem) in server.subsystems.iter().enumerate() {
+                let sub_path = format!("{}.subsystems[{}].name", path, sub_index);
+                if !subsystems.insert(subsystem.name.as_str()) {
+                    problems.warning(
+                        sub_path,
+                        format!(
+                            "duplicate subsystem \"{}\", it's queried once",
+                            subsystem.name
+                        ),
+                    );
+                } else if !KNOWN_SUBSYSTEMS.contains(&subsystem.name.as_str()) {
+                    problems.warning(
+                        sub_path,
+                        format!("\"{}\" is not a known ThingWorx subsystem", subsystem.name),
+                    );
+                }
+            }
+            for (jmx_index, jmx) in server.jmx_metrics.iter().flatten().enumerate() {
+                if let Some(ref alternative) = jmx.name_label_alternative {
+                    if !jmx.metrics.is_empty() && !jmx.metrics.contains(alternative) {
+                        problems.error(
+                            format!("{}.jmx_metrics[{}].name_label_alternative", path, jmx_index),
+                            format!("\"{}\" is not one of the metrics", alternative),
+                        );
+                    }
+                }
+            }
+            for (am_index, am) in server.arbitrary_metrics.iter().flatten().enumerate() {
+                if am.url.is_empty() {
+                    problems.error(
+                        format!("{}.arbitrary_metrics[{}].url", path, am_index),
+                        "the url is empty",
+                    );
+                }
+            }
+        }
+
+        self.validate_exports(&mut problems);
+        problems.0
+    }
+
+    fn validate_exports(&self, problems: &mut Problems) {
+        let mut enabled = 0;
+        let influx = &self.export_to_influxdb;
+        if influx.enabled {
+            enabled += 1;
+            let path = "export_to_influxdb";
+            problems.port(format!("{}.port", path), influx.port);
+            problems.protocol(format!("{}.protocol", path), &influx.protocol);
+            if !PRECISIONS.contains(&influx.precision.as_str()) {
+                problems.error(
+                    format!("{}.precision", path),
+                    format!(
+                        "unknown precision \"{}\", it should be ns, us, ms or s",
+                        influx.precision
+                    ),
+                );
+            }
+            problems.pair(
+                path,
+                ("username", influx.username.is_some()),
+                ("password", influx.password.is_some()),
+            );
+            match influx.api_version {
+                1 => {
+                    if influx.org.is_some() || influx.bucket.is_some() || influx.token.is_some() {
+                        problems.warning(
+                            format!("{}.api_version", path),
+                            "org, bucket and token are only used with api_version 2",
+                        );
+                    }
+                }
+                2 => {
+                    if influx.token.is_none() {
+                        problems.warning(
+                            format!("{}.token", path),
+                            "api_version 2 usually needs a token",
+                        );
+                    }
+                    if influx.username.is_some() {
+                        problems.warning(
+                            format!("{}.username", path),
+                            "username and password are only used with api_version 1",
+                        );
+                    }
+                }
+                version => problems.error(
+                    format!("{}.api_version", path),
+                    format!("unknown api_version {}, it should be 1 or 2", version),
+                ),
+            }
+        }
+
+        if let Some(file) = self.export_to_file.as_ref().filter(|c| c.enabled) {
+            enabled += 1;
+            if file.directory.is_empty() {
+                problems.error("export_to_file.directory", "the directory is empty");
+            }
+            // the spool and the file retention would remove each other's files.
+            let spool = influx.spool.as_ref().filter(|_| influx.enabled);
+            if spool.map_or(false, |spool| {
+                Path::new(&spool.directory) == Path::new(&file.directory)
+            }) {
+                problems.error(
+                    "export_to_influxdb.spool.directory",
+                    "the spool directory is the directory of export_to_file",
+                );
+            }
+        }
+
+        if let Some(prometheus) = self.export_to_prometheus.as_ref().filter(|c| c.enabled) {
+            enabled += 1;
+            let path = "export_to_prometheus";
+            problems.port(format!("{}.port", path), prometheus.port);
+            problems.buckets(
+                format!("{}.response_time_bucket_bin", path),
+                &prometheus.response_time_bucket_bin,
+            );
+            problems.pair(
+                path,
+                ("tls_cert", prometheus.tls_cert.is_some()),
+                ("tls_key", prometheus.tls_key.is_some()),
+            );
+            problems.pair(
+                path,
+                ("username", prometheus.username.is_some()),
+                ("password", prometheus.password.is_some()),
+            );
+        }
+
+        if let Some(remote_write) = self.export_to_remote_write.as_ref().filter(|c| c.enabled) {
+            enabled += 1;
+            let path = "export_to_remote_write";
+            problems.url(format!("{}.url", path), &remote_write.url);
+            problems.pair(
+                path,
+                ("username", remote_write.username.is_some()),
+                ("password", remote_write.password.is_some()),
+            );
+            if remote_write.username.is_some() && remote_write.bearer_token.is_some() {
+                problems.error(
+                    format!("{}.bearer_token", path),
+                    "basic authentication and bearer_token can't be used together",
+                );
+            }
+        }
+
+        if let Some(otlp) = self.export_to_otlp.as_ref().filter(|c| c.enabled) {
+            enabled += 1;
+            problems.url("export_to_otlp.endpoint".to_string(), &otlp.endpoint);
+            problems.buckets(
+                "export_to_otlp.response_time_bucket_bin".to_string(),
+                &otlp.response_time_bucket_bin,
+            );
+        }
+
+        if let Some(graphite) = self.export_to_graphite.as_ref().filter(|c| c.enabled) {
+            enabled += 1;
+            problems.port("export_to_graphite.port".to_string(), graphite.port);
+        }
+
+        if let Some(statsd) = self.export_to_statsd.as_ref().filter(|c| c.enabled) {
+            enabled += 1;
+            problems.port("export_to_statsd.port".to_string(), statsd.port);
+        }
+
+        if enabled == 0 {
+            problems.warning(
+                "",
+                "no export is enabled, the metrics will be collected but not stored",
+            );
+        }
+    }
+}
+
+/// Checks the configuration file and prints every problem with its line, returns false when
+/// there is an error.
+pub fn check_file(file_name: &str) -> anyhow::Result<bool> {
+    let contents = fs::read_to_string(file_name)?;
+    let mut errors = 0;
+    let mut warnings = 0;
+    let (files, mut diagnostics) = diagnose(file_name, &contents);
+    diagnostics.sort_by_key(|(file, line, _)| (*file, line.unwrap_or(usize::MAX)));
+    for (file, line, problem) in diagnostics {
+        let location = match line {
+            Some(line) => format!("{}:{}", files[file], line),
+            None => files[file].clone(),
+        };
+        let severity = match problem.severity {
+            Severity::Error => {
+                errors += 1;
+                "error"
+            }
+            Severity::Warning => {
+                warnings += 1;
+                "warning"
+            }
+        };
+        if problem.path.is_empty() {
+            println!("{}: {}: {}", location, severity, problem.message);
+        } else {
+            println!(
+                "{}: {}: {}: {}",
+                location, severity, problem.path, problem.message
+            );
+        }
+    }
+    println!(
+        "{}: {} error(s), {} warning(s)",
+        file_name, errors, warnings
+    );
+    Ok(errors == 0)
+}
+
+// the file, by its index in the list of files, and the line of a problem.
+type Diagnostic = (usize, Option<usize>, Problem);
+
+fn error(path: impl Into<String>, message: impl Into<String>) -> Problem {
+    Problem {
+        severity: Severity::Error,
+        path: path.into(),
+        message: message.into(),
+    }
+}
+
+/// Every problem of the configuration and of the files it includes, with its line when it's
+/// known. Returns the names of the files, the configuration file first.
+fn diagnose(file_name: &str, contents: &str) -> (Vec<String>, Vec<Diagnostic>) {
+    let mut names = vec![file_name.to_string()];
+    let (main, main_lines) = match parse(contents) {
+        Ok(parsed) => parsed,
+        Err((line, problem)) => return (names, vec![(0, line, problem)]),
+    };
+    let included = match include::included_files(file_name, &main) {
+        Ok(included) => included,
+        Err(e) => {
+            let problem = error("include", format!("{:#}", e));
+            return (names, vec![(0, main_lines.line("include"), problem)]);
+        }
+    };
+    let mut files = vec![ConfigFile {
+        path: PathBuf::from(file_name),
+        document: main,
+    }];
+    let mut lines = vec![main_lines];
+    for path in included {
+        let index = names.len();
+        names.push(path.display().to_string());
+        let parsed = fs::read_to_string(&path)
+            .map_err(|e| (None, error("", e.to_string())))
+            .and_then(|contents| parse(&contents));
+        match parsed {
+            Ok((document, file_lines)) => {
+                files.push(ConfigFile { path, document });
+                lines.push(file_lines);
+            }
+            Err((line, problem)) => return (names, vec![(index, line, problem)]),
+        }
+    }
+    // the references are resolved in each file, relative to it.
+    let mut diagnostics = vec![];
+    for (index, file) in files.iter_mut().enumerate() {
+        for (path, e) in secrets::unresolved(&mut file.document, &file.path) {
+            let line = lines[index].line(&path);
+            diagnostics.push((index, line, error(path, format!("{:#}", e))));
+        }
+    }
+    let single_file = files.len() == 1;
+    let (mut document, origins) = match include::merge(files) {
+        Ok(merged) => merged,
+        Err(e) => {
+            diagnostics.push((0, None, error("", format!("{:#}", e))));
+            return (names, diagnostics);
+        }
+    };
+    include::inherit_default_subsystems(&mut document);
+
+    // the path of a problem becomes the path in its file.
+    let locate = |mut problem: Problem| {
+        let (file, path) = origins.locate(&problem.path);
+        let line = lines[file].line(&path);
+        problem.path = path;
+        (file, line, problem)
+    };
+
+    // the errors of the file itself have a location, the references are all strings.
+    if single_file {
+        if let Err(e) = serde_yaml::from_str::<TestConfig>(contents) {
+            diagnostics.push((0, e.location().map(|l| l.line()), error("", e.to_string())));
+            return (names, diagnostics);
+        }
+    }
+    let config: TestConfig = match serde_yaml::from_value(document) {
+        Ok(config) => config,
+        Err(e) => {
+            diagnostics.push((0, None, error("", e.to_string())));
+            return (names, diagnostics);
+        }
+    };
+    for problem in config.validate() {
+        diagnostics.push(locate(problem));
+    }
+    (names, diagnostics)
+}
+
+// a syntax error has a line.
+fn parse(contents: &str) -> Result<(Value, LineIndex), (Option<usize>, Problem)> {
+    let lines =
+        LineIndex::build(contents).map_err(|(line, message)| (Some(line), error("", message)))?;
+    let document = serde_yaml::from_str(contents)
+        .map_err(|e| (e.location().map(|l| l.line()), error("", e.to_string())))?;
+    Ok((document, lines))
+}
+
+enum Frame {
+    // the key waiting for its value.
+    Mapping { path: String, key: Option<String> },
+    Sequence { path: String, index: usize },
+}
+
+/// The line of every key and sequence item, by YAML path.
+#[derive(Default)]
+struct LineIndex {
+    lines: HashMap<String, usize>,
+    stack: Vec<Frame>,
+}
+
+impl LineIndex {
+    fn build(contents: &str) -> Result<Self, (usize, String)> {
+        let mut index = LineIndex::default();
+        let mut parser = Parser::new(contents.chars());
+        parser
+            .load(&mut index, false)
+            .map_err(|e| (e.marker().line(), e.to_string()))?;
+        Ok(index)
+    }
+
+    /// The line of the path, or of its closest parent.
+    fn line(&self, path: &str) -> Option<usize> {
+        let mut path = path;
+        loop {
+            if let Some(line) = self.lines.get(path) {
+                return Some(*line);
+            }
+            path = &path[..path.rfind(['.', '['])?];
+        }
+    }
+
+    /// The path of the value that starts with this event, none for a mapping key.
+    fn value_path(&mut self, event: &Event, line: usize) -> Option<String> {
+        match self.stack.last_mut() {
+            None => Some(String::new()),
+            Some(Frame::Sequence { path, index }) => {
+                let item = format!("{}[{}]", path, index);
+                *index += 1;
+                self.lines.entry(item.clone()).or_insert(line);
+                Some(item)
+            }
+            Some(Frame::Mapping { path, key }) => match key.take() {
+                Some(key) if path.is_empty() => Some(key),
+                Some(key) => Some(format!("{}.{}", path, key)),
+                None => {
+                    let name = match event {
+                        Event::Scalar(name, ..) => name.clone(),
+                        _ => "?".to_string(),
+                    };
+                    let full = if path.is_empty() {
+                        name.clone()
+                    } else {
+                        format!("{}.{}", path, name)
+                    };
+                    self.lines.entry(full).or_insert(line);
+                    *key = Some(name);
+                    None
+                }
+            },
+        }
+    }
+}
+
+impl MarkedEventReceiver for LineIndex {
+    fn on_event(&mut self, event: Event, mark: Marker) {
+        match event {
+            Event::MappingEnd | Event::SequenceEnd => {
+                self.stack.pop();
+            }
+            Event::MappingStart(_)
+            | Event::SequenceStart(_)
+            | Event::Scalar(..)
+            | Event::Alias(_) => {
+                let path = match self.value_path(&event, mark.line()) {
+                    Some(path) => path,
+                    // a complex mapping key is not indexed.
+                    None if !matches!(event, Event::Scalar(..)) => "?".to_string(),
+                    None => return,
+                };
+                match event {
+                    Event::MappingStart(_) => self.stack.push(Frame::Mapping { path, key: None }),
+                    Event::SequenceStart(_) => self.stack.push(Frame::Sequence { path, index: 0 }),
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diagnose() {
+        let contents = "
+export_to_influxdb: {enabled: false, server_name: localhost}
+export_to_prometheus:
+  enabled: true
+  response_time_bucket_bin: [10.0, 5.0]
+thingworx_servers:
+  - name: p1
+    host: localhost
+    port: 8080
+    app_key: key
+    subsystems: []
+  - name: p1
+    host: localhost
+    port: 0
+    protocol: htps
+    subsystems:
+      - name: EventProcesingSubsystem
+    jmx_metrics:
+      - name: jmx_memory_status
+        object_name_pattern: 'java.lang:type=Memory'
+        name_label_alternative: Name
+        metrics: [HeapMemoryUsage]
+    scrap_interval: 5
+    scrap_jitter: 5
+";
+        let found: Vec<(Option<usize>, Severity, String)> = diagnose("config.yaml", contents)
+            .1
+            .into_iter()
+            .map(|(_, line, problem)| (line, problem.severity, problem.path))
+            .collect();
+        let expected = [
+            (
+                Some(11),
+                Severity::Warning,
+                "thingworx_servers[0].subsystems",
+            ),
+            (Some(12), Severity::Error, "thingworx_servers[1].name"),
+            (Some(15), Severity::Error, "thingworx_servers[1].protocol"),
+            (Some(14), Severity::Error, "thingworx_servers[1].port"),
+            // no app_key line, the line of the server.
+            (Some(12), Severity::Error, "thingworx_servers[1].app_key"),
+            (
+                Some(24),
+                Severity::Error,
+                "thingworx_servers[1].scrap_jitter",
+            ),
+            (
+                Some(17),
+                Severity::Warning,
+                "thingworx_servers[1].subsystems[0].name",
+            ),
+            (
+                Some(21),
+                Severity::Error,
+                "thingworx_servers[1].jmx_metrics[0].name_label_alternative",
+            ),
+            (
+                Some(5),
+                Severity::Error,
+                "export_to_prometheus.response_time_bucket_bin",
+            ),
+        ];
+        let expected: Vec<(Option<usize>, Severity, String)> = expected
+            .iter()
+            .map(|(line, severity, path)| (*line, *severity, path.to_string()))
+            .collect();
+        assert_eq!(found, expected);
+
+        // the location of a type error.
+        let (_, found) = diagnose("config.yaml", "scrap_interval: thirty\n");
+        assert_eq!(found[0].1, Some(1));
+        assert!(found[0].2.message.contains("scrap_interval"));
+
+        let (_, found) = diagnose(
+            "config.yaml",
+            "export_to_influxdb: {enabled: false, server_name: localhost}
+thingworx_servers:
+  - {name: p1, host: localhost, port: 8443, protocol: https, app_key: key,
+     tls: {verify: false}, subsystems: [{name: PlatformSubsystem}]}
+",
+        );
+        assert!(found.iter().any(|(_, line, problem)| problem.path
+            == "thingworx_servers[0].tls.verify"
+            && *line == Some(4)));
+
+        let (_, found) = diagnose(
+            "config.yaml",
+            "export_to_influxdb: {enabled: true, server_name: localhost, spool: {directory: /var/tsample}}
+export_to_file: {enabled: true, auto_create_folder: false, directory: /var/tsample/}
+thingworx_servers: []
+",
+        );
+        assert!(found.iter().any(|(_, line, problem)| problem.path
+            == "export_to_influxdb.spool.directory"
+            && problem.severity == Severity::Error
+            && *line == Some(1)));
+    }
+
+    #[test]
+    fn test_diagnose_included_files() {
+        let directory =
+            std::env::temp_dir().join(format!("tsample-check-include-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(directory.join("conf.d")).unwrap();
+        let main = directory.join("config.yaml");
+        let contents = "
+export_to_influxdb: {enabled: true, server_name: localhost}
+default_subsystems: [{name: EventProcessingSubsystem}]
+thingworx_servers:
+  - {name: p1, host: localhost, port: 8080, app_key: key}
+";
+        fs::write(
+            directory.join("conf.d/team.yaml"),
+            "thingworx_servers:
+  - name: p2
+    host: localhost
+    port: 8080
+    app_key: key
+  - name: p1
+    host: localhost
+    port: 0
+    app_key: key
+",
+        )
+        .unwrap();
+        let (files, found) = diagnose(main.to_str().unwrap(), contents);
+        assert!(files[1].ends_with("team.yaml"));
+        let found: Vec<(usize, Option<usize>, String)> = found
+            .into_iter()
+            .map(|(file, line, problem)| (file, line, problem.path))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (1, Some(6), "thingworx_servers[1].name".to_string()),
+                (1, Some(8), "thingworx_servers[1].port".to_string()),
+            ]
+        );
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
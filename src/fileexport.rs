@@ -0,0 +1,560 @@
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use flate2::{write::GzEncoder, Compression};
+use influxdb::Type;
+use serde_json::{Map, Value as JsonValue};
+
+use crate::lineprotocol::{self, timestamp_in};
+use crate::sink::Sink;
+use crate::spec::WriteSpec;
+use crate::testconfig::{ExportToFile, FileFormat, FileRotation};
+
+// Export file formats, all timestamps are milliseconds since the Unix epoch (UTC):
+//
+// line_protocol: InfluxDB line protocol, one point per line. It can be re-imported
+//   into InfluxDB with precision "ms", e.g. `influx write --precision ms -f metrics.lp`.
+// json_lines: one JSON object per point and per line, keys are sorted:
+//   {"fields":{"queueSize":0.0},"measurement":"...","tags":{"Platform":"..."},"timestamp":1683600000123}
+// csv: one row per field, with the header "timestamp,measurement,tags,field,value".
+//   tags are joined as "key=value;key=value", e.g. pandas.read_csv("metrics.csv").
+
+pub struct FileSink {
+    format: FileFormat,
+    writer: ExportFileWriter,
+}
+
+impl FileSink {
+    pub fn open(file_config: ExportToFile) -> anyhow::Result<Self> {
+        let path = Path::new(&file_config.directory);
+        if !(path.is_dir() && path.exists()) {
+            if file_config.auto_create_folder {
+                std::fs::create_dir_all(&file_config.directory)?;
+            } else {
+                return Err(anyhow::anyhow!("directory not exist"));
+            }
+        }
+        let format = file_config.format;
+        let writer = ExportFileWriter::open(file_config, Utc::now())?;
+        Ok(FileSink { format, writer })
+    }
+}
+
+#[async_trait]
+impl Sink for FileSink {
+    async fn write(&mut self, write_specs: &[WriteSpec]) -> anyhow::Result<usize> {
+        let mut content = String::new();
+        for spec in write_specs.iter() {
+            encode(spec, self.format, &mut content);
+        }
+        self.writer.write(&content, Utc::now())?;
+        Ok(write_specs.len())
+    }
+}
+
+/// Appends to `metrics-{period}[.{part}].{extension}` in the export directory.
+/// The period is the UTC day (2023-05-09) or hour (2023-05-09-13), the part number
+/// is only used with `max_file_size_mb`. All numbers are zero-padded, so the file names
+/// sort in chronological order.
+struct ExportFileWriter {
+    config: ExportToFile,
+    directory: PathBuf,
+    period: String,
+    part: u32,
+    path: PathBuf,
+    file: fs::File,
+    size: u64,
+}
+
+impl ExportFileWriter {
+    fn open(config: ExportToFile, now: DateTime<Utc>) -> anyhow::Result<Self> {
+        let directory = PathBuf::from(&config.directory);
+        let period = get_period(now, config.rotation);
+        // continue with the last part of the current period after a restart.
+        let part = if config.max_file_size_mb.is_some() {
+            last_part(&directory, &period, config.format)
+        } else {
+            0
+        };
+        let path = directory.join(get_filename(&period, part, &config));
+        let (file, size) = open_export_file(&path, config.format)?;
+        let writer = ExportFileWriter {
+            config,
+            directory,
+            period,
+            part,
+            path,
+            file,
+            size,
+        };
+        writer.housekeeping();
+        Ok(writer)
+    }
+
+    fn write(&mut self, content: &str, now: DateTime<Utc>) -> anyhow::Result<()> {
+        let period = get_period(now, self.config.rotation);
+        if period != self.period {
+            self.period = period;
+            self.part = 0;
+            self.rotate()?;
+        } else if let Some(max_file_size_mb) = self.config.max_file_size_mb {
+            if self.size > 0
+                && self.size + content.len() as u64 > max_file_size_mb.saturating_mul(1024 * 1024)
+            {
+                self.part += 1;
+                self.rotate()?;
+            }
+        }
+        self.file.write_all(content.as_bytes())?;
+        self.size += content.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> anyhow::Result<()> {
+        self.path = self
+            .directory
+            .join(get_filename(&self.period, self.part, &self.config));
+        let (file, size) = open_export_file(&self.path, self.config.format)?;
+        self.file = file;
+        self.size = size;
+        log::info!("export file rotated to:{:?}", self.path);
+        self.housekeeping();
+        Ok(())
+    }
+
+    /// Compresses the closed files and applies the retention, in the background.
+    fn housekeeping(&self) {
+        if !self.config.compress
+            && self.config.retention_count.is_none()
+            && self.config.retention_age.is_none()
+        {
+            return;
+        }
+        let directory = self.directory.clone();
+        let current = self.path.clone();
+        let config = self.config.clone();
+        tokio::task::spawn_blocking(move || {
+            if config.compress {
+                compress_closed_files(&directory, &current, config.format);
+            }
+            apply_retention(&directory, &current, &config);
+        });
+    }
+}
+
+fn open_export_file(export_file: &Path, format: FileFormat) -> anyhow::Result<(fs::File, u64)> {
+    let mut file = fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(export_file)?;
+    let mut size = file.metadata()?.len();
+    if format == FileFormat::Csv && size == 0 {
+        file.write_all(CSV_HEADER.as_bytes())?;
+        size = CSV_HEADER.len() as u64;
+    }
+    Ok((file, size))
+}
+
+fn compress_closed_files(directory: &Path, current: &Path, format: FileFormat) {
+    for (path, _) in list_export_files(directory) {
+        if path == current || path.extension().and_then(|e| e.to_str()) != Some(format.extension())
+        {
+            continue;
+        }
+        let mut gz_path = path.clone().into_os_string();
+        gz_path.push(".gz");
+        match compress_file(&path, Path::new(&gz_path)) {
+            Ok(_) => {
+                log::debug!("export file compressed:{:?}", gz_path);
+                if let Err(e) = fs::remove_file(&path) {
+                    log::error!("failed to remove:{:?}, error:{:?}", path, e);
+                }
+            }
+            Err(e) => {
+                log::error!("failed to compress:{:?}, error:{:?}", path, e);
+                let _ = fs::remove_file(&gz_path);
+            }
+        }
+    }
+}
+
+fn compress_file(source: &Path, target: &Path) -> std::io::Result<()> {
+    let mut input = fs::File::open(source)?;
+    let output = fs::File::create(target)?;
+    let mut encoder = GzEncoder::new(output, Compression::default());
+    std::io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?.sync_all()
+}
+
+fn apply_retention(directory: &Path, current: &Path, config: &ExportToFile) {
+    let files = list_export_files(directory)
+        .into_iter()
+        .filter(|(path, _)| path != current)
+        .filter_map(|(path, modified)| {
+            let key = file_key(path.file_name()?.to_str()?, config)?;
+            Some((key, path, modified))
+        })
+        .collect::<Vec<_>>();
+    let max_age = config.retention_age.map(Duration::from_secs);
+    // the current file counts as one of the files to keep.
+    let keep = config.retention_count.map(|count| count.saturating_sub(1));
+    for path in files_to_remove(files, keep, max_age, SystemTime::now()) {
+        match fs::remove_file(&path) {
+            Ok(_) => log::info!("export file removed by the retention policy:{:?}", path),
+            Err(e) => log::error!("failed to remove:{:?}, error:{:?}", path, e),
+        }
+    }
+}
+
+/// The period and the part of a file named like the files written with this configuration.
+/// The other files, like the unpadded names of the older versions or the files of another
+/// rotation, are left alone by the retention.
+fn file_key(name: &str, config: &ExportToFile) -> Option<FileKey> {
+    let name = name.strip_suffix(".gz").unwrap_or(name);
+    let rest = name
+        .strip_prefix(FILE_PREFIX)?
+        .strip_suffix(config.format.extension())?
+        .strip_suffix('.')?;
+    let (period, part) = match rest.split_once('.') {
+        Some((period, part)) if part.len() >= 3 && part.bytes().all(|b| b.is_ascii_digit()) => {
+            (period, part.parse().ok()?)
+        }
+        Some(_) => return None,
+        None => (rest, 0),
+    };
+    let pattern = match config.rotation {
+        FileRotation::Daily => "0000-00-00",
+        FileRotation::Hourly => "0000-00-00-00",
+    };
+    let matches = period.len() == pattern.len()
+        && period.bytes().zip(pattern.bytes()).all(|(c, p)| {
+            if p == b'0' {
+                c.is_ascii_digit()
+            } else {
+                c == p
+            }
+        });
+    if !matches {
+        return None;
+    }
+    Some((period.to_string(), part))
+}
+
+// the zero-padded period and the part number, in chronological order.
+type FileKey = (String, u32);
+
+/// Returns the files beyond the newest `keep` ones, and the files older than `max_age`.
+/// `files` are sorted by period and part, which is the chronological order.
+fn files_to_remove(
+    mut files: Vec<(FileKey, PathBuf, SystemTime)>,
+    keep: Option<usize>,
+    max_age: Option<Duration>,
+    now: SystemTime,
+) -> Vec<PathBuf> {
+    files.sort();
+    let count_limit = match keep {
+        Some(keep) => files.len().saturating_sub(keep),
+        None => 0,
+    };
+    files
+        .into_iter()
+        .enumerate()
+        .filter(|(index, (_, _, modified))| {
+            let too_old = match max_age {
+                Some(max_age) => now.duration_since(*modified).unwrap_or_default() > max_age,
+                None => false,
+            };
+            *index < count_limit || too_old
+        })
+        .map(|(_, (_, path, _))| path)
+        .collect()
+}
+
+fn list_export_files(directory: &Path) -> Vec<(PathBuf, SystemTime)> {
+    let mut files = vec![];
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(e) => {
+            log::error!("failed to read directory:{:?}, error:{:?}", directory, e);
+            return files;
+        }
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let is_export_file = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .map(|name| name.starts_with(FILE_PREFIX))
+            .unwrap_or(false);
+        if !is_export_file || !path.is_file() {
+            continue;
+        }
+        let modified = entry
+            .metadata()
+            .and_then(|m| m.modified())
+            .unwrap_or_else(|_| SystemTime::now());
+        files.push((path, modified));
+    }
+    files
+}
+
+fn last_part(directory: &Path, period: &str, format: FileFormat) -> u32 {
+    let prefix = format!("{}{}.", FILE_PREFIX, period);
+    let suffix = format!(".{}", format.extension());
+    list_export_files(directory)
+        .iter()
+        .filter_map(|(path, _)| {
+            let name = path.file_name()?.to_str()?;
+            name.strip_prefix(&prefix)?
+                .strip_suffix(&suffix)?
+                .parse::<u32>()
+                .ok()
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+fn encode(spec: &WriteSpec, format: FileFormat, content: &mut String) {
+    match format {
+        FileFormat::LineProtocol => {
+            if let Some(line) = lineprotocol::encode(spec, "ms", false) {
+                content.push_str(&line);
+                content.push('\n');
+            }
+        }
+        FileFormat::JsonLines => {
+            content.push_str(&encode_json_line(spec));
+            content.push('\n');
+        }
+        FileFormat::Csv => encode_csv_rows(spec, content),
+    }
+}
+
+const CSV_HEADER: &str = "timestamp,measurement,tags,field,value\n";
+
+fn encode_json_line(spec: &WriteSpec) -> String {
+    let mut tags = Map::new();
+    for (key, value) in spec.tags.iter() {
+        tags.insert(key.clone(), JsonValue::String(value.to_string()));
+    }
+    let mut fields = Map::new();
+    for (key, value) in spec.fields.iter() {
+        let value = match value {
+            Type::Boolean(value) => JsonValue::Bool(*value),
+            // NaN and infinity are not valid JSON numbers, they become null.
+            Type::Float(value) => serde_json::Number::from_f64(*value)
+                .map(JsonValue::Number)
+                .unwrap_or(JsonValue::Null),
+            Type::SignedInteger(value) => JsonValue::Number((*value).into()),
+            Type::UnsignedInteger(value) => JsonValue::Number((*value).into()),
+            Type::Text(value) => JsonValue::String(value.clone()),
+        };
+        fields.insert(key.clone(), value);
+    }
+    let mut object = Map::new();
+    object.insert(
+        "measurement".to_string(),
+        JsonValue::String(spec.measurement.clone()),
+    );
+    object.insert("tags".to_string(), JsonValue::Object(tags));
+    object.insert("fields".to_string(), JsonValue::Object(fields));
+    object.insert(
+        "timestamp".to_string(),
+        JsonValue::Number(timestamp_in(spec.timestamp, "ms").into()),
+    );
+    JsonValue::Object(object).to_string()
+}
+
+fn encode_csv_rows(spec: &WriteSpec, content: &mut String) {
+    let timestamp = timestamp_in(spec.timestamp, "ms").to_string();
+    let tags = spec
+        .tags
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<String>>()
+        .join(";");
+    for (field, value) in spec.fields.iter() {
+        let row = [
+            timestamp.as_str(),
+            &spec.measurement,
+            &tags,
+            field,
+            &value.to_string(),
+        ]
+        .iter()
+        .map(|column| csv_escape(column))
+        .collect::<Vec<String>>()
+        .join(",");
+        content.push_str(&row);
+        content.push('\n');
+    }
+}
+
+fn csv_escape(column: &str) -> String {
+    if column.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", column.replace('"', "\"\""))
+    } else {
+        column.to_string()
+    }
+}
+
+const FILE_PREFIX: &str = "metrics-";
+
+fn get_period(now: DateTime<Utc>, rotation: FileRotation) -> String {
+    match rotation {
+        FileRotation::Daily => now.format("%Y-%m-%d").to_string(),
+        FileRotation::Hourly => now.format("%Y-%m-%d-%H").to_string(),
+    }
+}
+
+fn get_filename(period: &str, part: u32, config: &ExportToFile) -> String {
+    match config.max_file_size_mb {
+        Some(_) => format!(
+            "{}{}.{:03}.{}",
+            FILE_PREFIX,
+            period,
+            part,
+            config.format.extension()
+        ),
+        None => format!("{}{}.{}", FILE_PREFIX, period, config.format.extension()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use influxdb::Timestamp;
+
+    fn sample_spec() -> WriteSpec {
+        WriteSpec::new(Timestamp::Milliseconds(1683600000123), "ValueStream")
+            .add_tag("Provider", Type::Text("Default".to_string()))
+            .add_tag("Platform", Type::Text("platform1".to_string()))
+            .add_field("queueSize", Type::Float(2.5))
+            .add_field("ResponseTime", Type::SignedInteger(42))
+            .add_field("status", Type::Text("ok, \"fine\"".to_string()))
+    }
+
+    #[test]
+    fn test_encode_json_line() {
+        let mut content = String::new();
+        encode(&sample_spec(), FileFormat::JsonLines, &mut content);
+        assert_eq!(
+            content,
+            r#"{"fields":{"ResponseTime":42,"queueSize":2.5,"status":"ok, \"fine\""},"measurement":"ValueStream","tags":{"Platform":"platform1","Provider":"Default"},"timestamp":1683600000123}"#
+                .to_string()
+                + "\n"
+        );
+    }
+
+    #[test]
+    fn test_encode_csv() {
+        let mut content = String::new();
+        encode(&sample_spec(), FileFormat::Csv, &mut content);
+        assert_eq!(
+            content,
+            "1683600000123,ValueStream,Provider=Default;Platform=platform1,queueSize,2.5\n\
+             1683600000123,ValueStream,Provider=Default;Platform=platform1,ResponseTime,42\n\
+             1683600000123,ValueStream,Provider=Default;Platform=platform1,status,\"ok, \"\"fine\"\"\"\n"
+        );
+    }
+
+    #[test]
+    fn test_filename() {
+        let now = Utc.with_ymd_and_hms(2023, 5, 9, 7, 30, 0).unwrap();
+        let mut config = ExportToFile::default();
+        assert_eq!(
+            get_filename(&get_period(now, config.rotation), 0, &config),
+            "metrics-2023-05-09.lp"
+        );
+        config.rotation = FileRotation::Hourly;
+        config.max_file_size_mb = Some(100);
+        config.format = FileFormat::Csv;
+        assert_eq!(
+            get_filename(&get_period(now, config.rotation), 2, &config),
+            "metrics-2023-05-09-07.002.csv"
+        );
+    }
+
+    #[test]
+    fn test_files_to_remove() {
+        let now = SystemTime::now();
+        let hours_ago = |hours: u64| now - Duration::from_secs(hours * 3600);
+        let config = ExportToFile {
+            rotation: FileRotation::Hourly,
+            ..ExportToFile::default()
+        };
+        let files: Vec<_> = vec![
+            ("metrics-2023-05-09-02.lp.gz", hours_ago(3)),
+            ("metrics-2023-05-09-00.lp.gz", hours_ago(5)),
+            ("metrics-2023-05-09-01.lp.gz", hours_ago(4)),
+            // unpadded name of an older version, and a daily file.
+            ("metrics-2023-5-9.txt", hours_ago(9)),
+            ("metrics-2023-05-09.lp", hours_ago(9)),
+        ]
+        .into_iter()
+        .filter_map(|(name, modified)| {
+            Some((file_key(name, &config)?, PathBuf::from(name), modified))
+        })
+        .collect();
+        assert_eq!(files.len(), 3);
+        assert_eq!(
+            files_to_remove(files.clone(), Some(2), None, now),
+            vec![PathBuf::from("metrics-2023-05-09-00.lp.gz")]
+        );
+        assert_eq!(
+            files_to_remove(
+                files.clone(),
+                None,
+                Some(Duration::from_secs(4 * 3600 - 1)),
+                now
+            ),
+            vec![
+                PathBuf::from("metrics-2023-05-09-00.lp.gz"),
+                PathBuf::from("metrics-2023-05-09-01.lp.gz")
+            ]
+        );
+        assert!(files_to_remove(files, None, None, now).is_empty());
+
+        // the parts sort by number, after the file of the period without a part.
+        let config = ExportToFile {
+            max_file_size_mb: Some(100),
+            ..ExportToFile::default()
+        };
+        let mut keys: Vec<FileKey> = [
+            "metrics-2023-05-09.1000.lp",
+            "metrics-2023-05-09.999.lp.gz",
+            "metrics-2023-05-10.000.lp",
+            "metrics-2023-05-09.lp",
+        ]
+        .iter()
+        .filter_map(|name| file_key(name, &config))
+        .collect();
+        keys.sort();
+        assert_eq!(
+            keys,
+            vec![
+                ("2023-05-09".to_string(), 0),
+                ("2023-05-09".to_string(), 999),
+                ("2023-05-09".to_string(), 1000),
+                ("2023-05-10".to_string(), 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_encode_line_protocol() {
+        let mut content = String::new();
+        encode(&sample_spec(), FileFormat::LineProtocol, &mut content);
+        assert_eq!(
+            content,
+            "ValueStream,Provider=Default,Platform=platform1 queueSize=2.5,ResponseTime=42i,status=\"ok, \\\"fine\\\"\" 1683600000123\n"
+        );
+    }
+}
@@ -0,0 +1,348 @@
+use std::time::{Duration, Instant};
+
+use crate::lineprotocol::timestamp_in;
+use crate::prometheus::gauge_value;
+use crate::sink::Sink;
+use crate::spec::WriteSpec;
+use crate::testconfig::ExportToGraphite;
+use async_trait::async_trait;
+use tokio::{io::AsyncWriteExt, net::TcpStream};
+
+// Graphite plaintext protocol: "path value timestamp\n", the timestamp is in seconds.
+// With the tagged syntax (Graphite 1.1+) the path is followed by ";tag=value" pairs.
+// https://graphite.readthedocs.io/en/latest/feeding-carbon.html
+// https://graphite.readthedocs.io/en/latest/tags.html
+
+pub struct GraphiteSink {
+    template: Template,
+    tagged: bool,
+    writer: GraphiteWriter,
+}
+
+impl GraphiteSink {
+    pub fn new(config: &ExportToGraphite) -> anyhow::Result<Self> {
+        let address = format!("{}:{}", config.host, config.port);
+        log::info!(
+            "graphite service launched, address:{}, tagged:{}",
+            address,
+            config.tagged
+        );
+        Ok(GraphiteSink {
+            template: Template::parse(&config.template)?,
+            tagged: config.tagged,
+            writer: GraphiteWriter::new(address, config),
+        })
+    }
+}
+
+#[async_trait]
+impl Sink for GraphiteSink {
+    async fn write(&mut self, write_specs: &[WriteSpec]) -> anyhow::Result<usize> {
+        for spec in write_specs.iter() {
+            encode(spec, &self.template, self.tagged, &mut self.writer.pending);
+        }
+        self.writer.send().await;
+        Ok(write_specs.len())
+    }
+
+    // sends the pending lines once graphite is back.
+    async fn tick(&mut self) -> anyhow::Result<usize> {
+        self.writer.send().await;
+        Ok(0)
+    }
+
+    fn tick_interval(&self) -> Option<Duration> {
+        Some(Duration::from_secs(1))
+    }
+
+    async fn close(&mut self) -> anyhow::Result<usize> {
+        self.writer.send().await;
+        Ok(0)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Segment {
+    Text(String),
+    Measurement,
+    Field,
+    Tag(String),
+}
+
+/// A metric path template like `twx.{Platform}.{measurement}.{Provider}.{field}`.
+/// `{measurement}` and `{field}` are replaced by the spec values, any other name by the tag value.
+/// The path segments left empty (a missing tag) are removed.
+#[derive(Debug)]
+pub struct Template {
+    segments: Vec<Segment>,
+}
+
+impl Template {
+    pub fn parse(template: &str) -> anyhow::Result<Self> {
+        let mut segments = vec![];
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                segments.push(Segment::Text(rest[..start].to_string()));
+            }
+            let end = rest[start..].find('}').ok_or_else(|| {
+                anyhow::anyhow!("unclosed '{{' in graphite template:{}", template)
+            })?;
+            let name = &rest[start + 1..start + end];
+            segments.push(match name {
+                "measurement" => Segment::Measurement,
+                "field" => Segment::Field,
+                "" => {
+                    return Err(anyhow::anyhow!(
+                        "empty placeholder in graphite template:{}",
+                        template
+                    ))
+                }
+                _ => Segment::Tag(name.to_string()),
+            });
+            rest = &rest[start + end + 1..];
+        }
+        if !rest.is_empty() {
+            segments.push(Segment::Text(rest.to_string()));
+        }
+        if !segments.contains(&Segment::Field) {
+            return Err(anyhow::anyhow!(
+                "graphite template must contain {{field}}:{}",
+                template
+            ));
+        }
+        Ok(Template { segments })
+    }
+
+    pub fn render(&self, spec: &WriteSpec, field: &str) -> String {
+        let mut path = String::new();
+        for segment in self.segments.iter() {
+            match segment {
+                Segment::Text(text) => path.push_str(text),
+                Segment::Measurement => path.push_str(&sanitize(&spec.measurement)),
+                Segment::Field => path.push_str(&sanitize(field)),
+                Segment::Tag(name) => {
+                    if let Some((_, value)) = spec.tags.iter().find(|(key, _)| key == name) {
+                        path.push_str(&sanitize(&value.to_string()));
+                    }
+                }
+            }
+        }
+        path.split('.')
+            .filter(|segment| !segment.is_empty())
+            .collect::<Vec<_>>()
+            .join(".")
+    }
+}
+
+/// Appends one line per numeric field of the spec.
+pub fn encode(spec: &WriteSpec, template: &Template, tagged: bool, target: &mut String) {
+    let timestamp = timestamp_in(spec.timestamp, "s");
+    for (field, value) in spec.fields.iter() {
+        let value = match gauge_value(value) {
+            Some(value) if value.is_finite() => value,
+            _ => continue,
+        };
+        let path = template.render(spec, field);
+        if path.is_empty() {
+            continue;
+        }
+        target.push_str(&path);
+        if tagged {
+            for (key, value) in spec.tags.iter() {
+                let value = value.to_string();
+                if value.is_empty() {
+                    continue;
+                }
+                target.push(';');
+                target.push_str(&sanitize(key));
+                target.push('=');
+                target.push_str(&sanitize_tag_value(&value));
+            }
+        }
+        target.push(' ');
+        target.push_str(&value.to_string());
+        target.push(' ');
+        target.push_str(&timestamp.to_string());
+        target.push('\n');
+    }
+}
+
+/// Replaces everything but letters, digits, '-' and '_', so a value is always one path segment.
+fn sanitize(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+fn sanitize_tag_value(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| {
+            if c == ';' || c == '~' || c.is_whitespace() {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect()
+}
+
+/// Keeps one TCP connection to carbon, reconnecting with a growing delay when it's lost.
+/// Lines that couldn't be sent are kept up to `max_pending_mb`, the oldest are dropped first.
+struct GraphiteWriter {
+    address: String,
+    timeout: Duration,
+    stream: Option<TcpStream>,
+    pending: String,
+    max_pending_bytes: usize,
+    backoff: Duration,
+    max_backoff: Duration,
+    next_connect: Instant,
+}
+
+impl GraphiteWriter {
+    fn new(address: String, config: &ExportToGraphite) -> Self {
+        GraphiteWriter {
+            address,
+            timeout: Duration::from_secs(config.timeout.max(1)),
+            stream: None,
+            pending: String::new(),
+            max_pending_bytes: (config.max_pending_mb as usize).saturating_mul(1024 * 1024),
+            backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(config.max_reconnect_backoff.max(1)),
+            next_connect: Instant::now(),
+        }
+    }
+
+    async fn send(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+        if self.stream.is_none() && !self.connect().await {
+            self.trim_pending();
+            return;
+        }
+        if let Some(ref mut stream) = self.stream {
+            let result =
+                tokio::time::timeout(self.timeout, stream.write_all(self.pending.as_bytes())).await;
+            match result {
+                Ok(Ok(_)) => {
+                    log::debug!("{} bytes sent to graphite", self.pending.len());
+                    self.pending.clear();
+                }
+                Ok(Err(e)) => {
+                    log::error!("graphite write error:{:?}", e);
+                    self.disconnect();
+                }
+                Err(_) => {
+                    log::error!("graphite write timeout");
+                    self.disconnect();
+                }
+            }
+        }
+        self.trim_pending();
+    }
+
+    async fn connect(&mut self) -> bool {
+        if Instant::now() < self.next_connect {
+            return false;
+        }
+        match tokio::time::timeout(self.timeout, TcpStream::connect(&self.address)).await {
+            Ok(Ok(stream)) => {
+                log::info!("connected to graphite:{}", self.address);
+                self.stream = Some(stream);
+                self.backoff = Duration::from_secs(1);
+                true
+            }
+            Ok(Err(e)) => {
+                self.connect_failed(format!("{:?}", e));
+                false
+            }
+            Err(_) => {
+                self.connect_failed("timeout".to_string());
+                false
+            }
+        }
+    }
+
+    fn connect_failed(&mut self, error: String) {
+        log::warn!(
+            "failed to connect to graphite:{}, next try in {:?}, error:{}",
+            self.address,
+            self.backoff,
+            error
+        );
+        self.next_connect = Instant::now() + self.backoff;
+        self.backoff = (self.backoff * 2).min(self.max_backoff);
+    }
+
+    fn disconnect(&mut self) {
+        // the lines written before the error may or may not have arrived, they are sent again.
+        self.stream = None;
+        self.next_connect = Instant::now();
+    }
+
+    fn trim_pending(&mut self) {
+        if self.pending.len() <= self.max_pending_bytes {
+            return;
+        }
+        // drop the oldest lines.
+        let excess = self.pending.len() - self.max_pending_bytes;
+        let cut = match self.pending[excess..].find('\n') {
+            Some(position) => excess + position + 1,
+            None => self.pending.len(),
+        };
+        log::warn!("graphite is not available, {} bytes dropped", cut);
+        self.pending.drain(..cut);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use influxdb::{Timestamp, Type};
+
+    fn spec() -> WriteSpec {
+        WriteSpec::new(Timestamp::Milliseconds(1683600000123), "ValueStream")
+            .add_tag("Platform", Type::Text("twx.prod".to_string()))
+            .add_tag("Provider", Type::Text("".to_string()))
+            .add_tag("sub_name", Type::Text("a;b c".to_string()))
+            .add_field("queue size", Type::SignedInteger(3))
+            .add_field("desc", Type::Text("skipped".to_string()))
+    }
+
+    #[test]
+    fn test_template() {
+        let template = Template::parse("twx.{Platform}.{measurement}.{Provider}.{field}").unwrap();
+        assert_eq!(
+            template.render(&spec(), "queue size"),
+            "twx.twx_prod.ValueStream.queue_size"
+        );
+        assert!(Template::parse("twx.{measurement}").is_err());
+        assert!(Template::parse("twx.{field").is_err());
+    }
+
+    #[test]
+    fn test_encode() {
+        let template = Template::parse("twx.{Platform}.{measurement}.{Provider}.{field}").unwrap();
+        let mut lines = String::new();
+        encode(&spec(), &template, false, &mut lines);
+        assert_eq!(lines, "twx.twx_prod.ValueStream.queue_size 3 1683600000\n");
+
+        let template = Template::parse("twx.{measurement}.{field}").unwrap();
+        let mut lines = String::new();
+        encode(&spec(), &template, true, &mut lines);
+        assert_eq!(
+            lines,
+            "twx.ValueStream.queue_size;Platform=twx.prod;sub_name=a_b_c 3 1683600000\n"
+        );
+    }
+}
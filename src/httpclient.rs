@@ -0,0 +1,280 @@
+use std::{
+    collections::HashMap,
+    fs,
+    net::ToSocketAddrs,
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
+
+use crate::testconfig::{ServerTls, ThingworxServer};
+use anyhow::Context;
+use lazy_static::lazy_static;
+use reqwest::{Certificate, Client, Identity};
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::Instant,
+};
+
+// TCP keepalive probes on the pooled connections, so a dead connection is noticed between cycles.
+const TCP_KEEPALIVE: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, PartialEq)]
+struct ClientSettings {
+    host: String,
+    port: u16,
+    timeout: u64,
+    http2: bool,
+    gzip: bool,
+    pool_idle_timeout: u64,
+    tls: Option<ServerTls>,
+    max_concurrent_requests: Option<usize>,
+    max_requests_per_second: Option<f64>,
+}
+
+impl ClientSettings {
+    fn new(server: &ThingworxServer, timeout: u64) -> Self {
+        ClientSettings {
+            host: server.host.clone(),
+            port: server.port,
+            timeout,
+            http2: server.http2,
+            gzip: server.gzip,
+            pool_idle_timeout: server.pool_idle_timeout,
+            tls: server.tls.clone(),
+            max_concurrent_requests: server.max_concurrent_requests,
+            max_requests_per_second: server.max_requests_per_second,
+        }
+    }
+}
+
+struct ServerClient {
+    settings: ClientSettings,
+    client: Client,
+    limiter: Arc<Limiter>,
+}
+
+lazy_static! {
+    // one client per ThingWorx server name.
+    static ref CLIENTS: RwLock<HashMap<String, ServerClient>> = RwLock::new(HashMap::new());
+}
+
+/// Limits the requests in flight and the request rate of one server.
+#[derive(Debug)]
+struct Limiter {
+    concurrency: Option<Arc<Semaphore>>,
+    // the minimum time between the start of two requests.
+    spacing: Option<Duration>,
+    next_slot: Mutex<Instant>,
+}
+
+impl Limiter {
+    fn new(settings: &ClientSettings) -> Self {
+        Limiter {
+            concurrency: settings
+                .max_concurrent_requests
+                .map(|max| Arc::new(Semaphore::new(max.max(1)))),
+            spacing: settings
+                .max_requests_per_second
+                .filter(|rate| *rate > 0.0)
+                .map(|rate| Duration::from_secs_f64(1.0 / rate)),
+            next_slot: Mutex::new(Instant::now()),
+        }
+    }
+
+    async fn acquire(&self) -> Option<OwnedSemaphorePermit> {
+        let permit = match self.concurrency {
+            Some(ref semaphore) => Some(
+                semaphore
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .expect("Semaphore closed."),
+            ),
+            None => None,
+        };
+        if let Some(spacing) = self.spacing {
+            let slot = {
+                let mut next_slot = self.next_slot.lock().expect("Lock poisoned.");
+                let slot = (*next_slot).max(Instant::now());
+                *next_slot = slot + spacing;
+                slot
+            };
+            tokio::time::sleep_until(slot).await;
+        }
+        permit
+    }
+}
+
+/// Waits until a request to the server is allowed by its `max_concurrent_requests` and
+/// `max_requests_per_second`. The permit has to be kept until the response is read.
+pub async fn acquire(server_name: &str) -> Option<OwnedSemaphorePermit> {
+    let limiter = CLIENTS
+        .read()
+        .expect("Read Lock poisoned.")
+        .get(server_name)
+        .map(|server_client| server_client.limiter.clone());
+    match limiter {
+        Some(limiter) => limiter.acquire().await,
+        None => None,
+    }
+}
+
+/// The HTTP client of a ThingWorx server, created on first use and shared by all its queries,
+/// so the connections and TLS sessions are reused between the cycles.
+/// It's created again when the settings of the server change.
+pub fn client_for(server: &ThingworxServer, timeout: u64) -> anyhow::Result<Client> {
+    let settings = ClientSettings::new(server, timeout);
+    if let Some(server_client) = CLIENTS
+        .read()
+        .expect("Read Lock poisoned.")
+        .get(&server.name)
+    {
+        if server_client.settings == settings {
+            return Ok(server_client.client.clone());
+        }
+    }
+
+    let client = build_client(&settings)
+        .with_context(|| format!("failed to create http client for server:{}", server.name))?;
+    if settings.tls.as_ref().map_or(false, |tls| !tls.verify) && server.protocol == "https" {
+        log::warn!(
+            "the certificate of server:{} is not verified, tls.verify is false.",
+            server.name
+        );
+    }
+    log::info!(
+        "http client created for server:{}, http2:{}, gzip:{}",
+        server.name,
+        settings.http2,
+        settings.gzip
+    );
+    let limiter = Arc::new(Limiter::new(&settings));
+    CLIENTS.write().expect("Write Lock poisoned.").insert(
+        server.name.clone(),
+        ServerClient {
+            settings,
+            client: client.clone(),
+            limiter,
+        },
+    );
+    Ok(client)
+}
+
+fn build_client(settings: &ClientSettings) -> anyhow::Result<Client> {
+    let mut builder = Client::builder()
+        .timeout(Duration::from_secs(settings.timeout))
+        .gzip(settings.gzip)
+        .pool_idle_timeout(Duration::from_secs(settings.pool_idle_timeout))
+        .tcp_keepalive(TCP_KEEPALIVE);
+    // HTTP/2 is negotiated with ALPN, so only over TLS.
+    if !settings.http2 {
+        builder = builder.http1_only();
+    }
+
+    let tls = match settings.tls {
+        Some(ref tls) => tls,
+        None => return Ok(builder.build()?),
+    };
+    if !tls.verify {
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+    if let Some(ref ca_file) = tls.ca_file {
+        let pem =
+            fs::read(ca_file).with_context(|| format!("failed to read ca_file:{}", ca_file))?;
+        builder = builder.add_root_certificate(Certificate::from_pem(&pem)?);
+    }
+    match (tls.client_cert.as_ref(), tls.client_key.as_ref()) {
+        (Some(client_cert), Some(client_key)) => {
+            let mut pem = fs::read(client_cert)
+                .with_context(|| format!("failed to read client_cert:{}", client_cert))?;
+            pem.push(b'\n');
+            pem.extend(
+                fs::read(client_key)
+                    .with_context(|| format!("failed to read client_key:{}", client_key))?,
+            );
+            builder = builder.identity(Identity::from_pem(&pem)?);
+        }
+        (None, None) => {}
+        _ => {
+            return Err(anyhow::anyhow!(
+                "client_cert and client_key must be set together"
+            ))
+        }
+    }
+    if let Some(ref server_name) = tls.server_name {
+        // the URLs use the server name, it's resolved to the address of host.
+        // the port of the URL is used.
+        let address = (settings.host.as_str(), settings.port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("failed to resolve host:{}", settings.host))?;
+        builder = builder.resolve(server_name, address);
+    }
+    Ok(builder.build()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_rebuilt_on_change() {
+        let mut server: ThingworxServer = serde_yaml::from_str(
+            "{name: test_client, host: localhost, port: 8080, app_key: key, subsystems: []}",
+        )
+        .unwrap();
+        let settings = |name: &str| CLIENTS.read().unwrap().get(name).unwrap().settings.clone();
+
+        client_for(&server, 20).unwrap();
+        assert!(settings("test_client").gzip);
+        assert!(!settings("test_client").http2);
+        assert_eq!(settings("test_client").pool_idle_timeout, 90);
+
+        server.http2 = true;
+        client_for(&server, 20).unwrap();
+        assert!(settings("test_client").http2);
+    }
+
+    #[test]
+    fn test_tls_settings() {
+        let server: ThingworxServer = serde_yaml::from_str(
+            "{name: test_tls, host: 127.0.0.1, port: 8443, protocol: https, app_key: key, subsystems: [], \
+             tls: {server_name: twx.example.com, client_cert: cert.pem}}",
+        )
+        .unwrap();
+        assert_eq!(server.url_host(), "twx.example.com");
+        let tls = server.tls.as_ref().unwrap();
+        assert!(tls.verify);
+
+        let error = client_for(&server, 20).unwrap_err();
+        assert!(format!("{:?}", error).contains("client_cert and client_key must be set together"));
+    }
+
+    #[tokio::test]
+    async fn test_limiter() {
+        let settings = ClientSettings {
+            host: "localhost".to_string(),
+            port: 8080,
+            timeout: 20,
+            http2: false,
+            gzip: true,
+            pool_idle_timeout: 90,
+            tls: None,
+            max_concurrent_requests: Some(1),
+            max_requests_per_second: Some(20.0),
+        };
+        let limiter = Arc::new(Limiter::new(&settings));
+
+        let start = Instant::now();
+        let permit = limiter.acquire().await;
+        assert!(permit.is_some());
+        // the only permit is taken.
+        let blocked = tokio::time::timeout(Duration::from_millis(100), limiter.acquire()).await;
+        assert!(blocked.is_err());
+        drop(permit);
+        limiter.acquire().await;
+        limiter.acquire().await;
+        // the last request starts 50ms after the previous one.
+        assert!(start.elapsed() >= Duration::from_millis(150));
+    }
+}
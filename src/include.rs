@@ -0,0 +1,331 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use serde_yaml::{Mapping, Value};
+
+use crate::secrets;
+
+const INCLUDE_KEY: &str = "include";
+const SERVERS_KEY: &str = "thingworx_servers";
+const DEFAULT_SUBSYSTEMS_KEY: &str = "default_subsystems";
+// the yaml files of this directory, next to the main file, are always included.
+const CONF_D: &str = "conf.d";
+
+/// A configuration file, parsed.
+pub struct ConfigFile {
+    pub path: PathBuf,
+    pub document: Value,
+}
+
+/// The file and the path in that file of each server and top-level key of a merged
+/// configuration, to report the problems where they are.
+#[derive(Debug, Default)]
+pub struct Origins {
+    // (file, index in the file) of each server.
+    servers: Vec<(usize, usize)>,
+    keys: HashMap<String, usize>,
+}
+
+impl Origins {
+    /// The index of the file and the path in that file of a path of the merged configuration.
+    pub fn locate(&self, path: &str) -> (usize, String) {
+        if let Some(rest) = path
+            .strip_prefix(SERVERS_KEY)
+            .and_then(|p| p.strip_prefix('['))
+        {
+            if let Some((index, rest)) = rest.split_once(']') {
+                if let Some(&(file, local)) = index
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|index| self.servers.get(index))
+                {
+                    return (file, format!("{}[{}]{}", SERVERS_KEY, local, rest));
+                }
+            }
+        }
+        let key = path.split(['.', '[']).next().unwrap_or_default();
+        (self.keys.get(key).copied().unwrap_or(0), path.to_string())
+    }
+}
+
+/// Reads the configuration file and the files it includes, merged into one document.
+/// The references of each file are resolved before the merge, relative to that file.
+pub fn load(file_name: &str) -> anyhow::Result<Value> {
+    let main = read(Path::new(file_name))?;
+    let included = included_files(file_name, &main)?;
+    let mut files = vec![ConfigFile {
+        path: PathBuf::from(file_name),
+        document: main,
+    }];
+    for path in included {
+        files.push(ConfigFile {
+            document: read(&path)?,
+            path,
+        });
+    }
+    for file in files.iter_mut() {
+        secrets::interpolate(&mut file.document, &file.path)?;
+    }
+    let (mut document, _) = merge(files)?;
+    inherit_default_subsystems(&mut document);
+    Ok(document)
+}
+
+/// The configuration file and the files it includes, for the changes to be watched.
+/// Only the configuration file when it can't be parsed.
+pub fn watched_files(file_name: &str) -> Vec<PathBuf> {
+    let mut files = vec![PathBuf::from(file_name)];
+    if let Ok(document) = read(Path::new(file_name)) {
+        files.extend(included_files(file_name, &document).unwrap_or_default());
+    }
+    files
+}
+
+fn read(path: &Path) -> anyhow::Result<Value> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("failed to read configuration file {}", path.display()))?;
+    serde_yaml::from_str(&contents)
+        .with_context(|| format!("failed to parse configuration file {}", path.display()))
+}
+
+/// The files of the `include` list, a file or a directory of yaml files relative to the
+/// configuration file, then the yaml files of its conf.d directory.
+pub fn included_files(file_name: &str, document: &Value) -> anyhow::Result<Vec<PathBuf>> {
+    let base = Path::new(file_name).parent().unwrap_or(Path::new(""));
+    let entries = match document.get(INCLUDE_KEY) {
+        None | Some(Value::Null) => vec![],
+        Some(Value::String(entry)) => vec![entry.as_str()],
+        Some(Value::Sequence(entries)) => entries
+            .iter()
+            .map(|entry| {
+                entry
+                    .as_str()
+                    .ok_or_else(|| anyhow::anyhow!("{} accepts only file names", INCLUDE_KEY))
+            })
+            .collect::<anyhow::Result<_>>()?,
+        Some(_) => {
+            return Err(anyhow::anyhow!(
+                "{} must be a file name or a list of file names",
+                INCLUDE_KEY
+            ))
+        }
+    };
+
+    let mut files = vec![];
+    for entry in entries {
+        let path = base.join(entry);
+        if path.is_dir() {
+            files.extend(yaml_files(&path)?);
+        } else {
+            files.push(path);
+        }
+    }
+    let conf_d = base.join(CONF_D);
+    if conf_d.is_dir() {
+        files.extend(yaml_files(&conf_d)?);
+    }
+    let mut unique = vec![];
+    for file in files {
+        if !unique.contains(&file) {
+            unique.push(file);
+        }
+    }
+    Ok(unique)
+}
+
+// sorted by name, so the servers keep their order between two runs.
+fn yaml_files(directory: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    for entry in fs::read_dir(directory)
+        .with_context(|| format!("failed to read directory {}", directory.display()))?
+    {
+        let path = entry?.path();
+        let is_yaml = path
+            .extension()
+            .map_or(false, |extension| extension == "yaml" || extension == "yml");
+        if is_yaml && path.is_file() {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Merges the included files into the first one: their servers are appended to
+/// `thingworx_servers`, any other top-level key can be defined by one file only.
+pub fn merge(files: Vec<ConfigFile>) -> anyhow::Result<(Value, Origins)> {
+    let mut merged = Mapping::new();
+    let mut servers = vec![];
+    let mut has_servers = false;
+    let mut origins = Origins::default();
+    let mut owners: Vec<&Path> = vec![];
+
+    for (index, file) in files.iter().enumerate() {
+        owners.push(&file.path);
+        let mapping = match file.document {
+            Value::Mapping(ref mapping) => mapping,
+            Value::Null if index > 0 => continue,
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "configuration file {} is not a mapping",
+                    file.path.display()
+                ))
+            }
+        };
+        for (key, value) in mapping.iter() {
+            let name = key.as_str().unwrap_or_default();
+            if name == INCLUDE_KEY {
+                if index > 0 {
+                    return Err(anyhow::anyhow!(
+                        "{}: an included file can't include other files",
+                        file.path.display()
+                    ));
+                }
+                continue;
+            }
+            if name == SERVERS_KEY {
+                has_servers = true;
+                let file_servers = match value {
+                    Value::Sequence(file_servers) => file_servers,
+                    Value::Null => continue,
+                    _ => {
+                        return Err(anyhow::anyhow!(
+                            "{}: {} must be a list",
+                            file.path.display(),
+                            SERVERS_KEY
+                        ))
+                    }
+                };
+                for (local, server) in file_servers.iter().enumerate() {
+                    servers.push(server.clone());
+                    origins.servers.push((index, local));
+                }
+                continue;
+            }
+            if let Some(&owner) = origins.keys.get(name) {
+                return Err(anyhow::anyhow!(
+                    "{} is defined in both {} and {}",
+                    name,
+                    owners[owner].display(),
+                    file.path.display()
+                ));
+            }
+            origins.keys.insert(name.to_string(), index);
+            merged.insert(key.clone(), value.clone());
+        }
+    }
+    // still missing when no file has servers, it's reported like before.
+    if has_servers {
+        merged.insert(Value::from(SERVERS_KEY), Value::Sequence(servers));
+    }
+    Ok((Value::Mapping(merged), origins))
+}
+
+/// Gives `default_subsystems` to the servers without a `subsystems` list.
+pub fn inherit_default_subsystems(document: &mut Value) {
+    let defaults = match document.get(DEFAULT_SUBSYSTEMS_KEY) {
+        Some(defaults) if !defaults.is_null() => defaults.clone(),
+        _ => return,
+    };
+    if let Some(Value::Sequence(servers)) = document.get_mut(SERVERS_KEY) {
+        for server in servers.iter_mut() {
+            if let Value::Mapping(server) = server {
+                let key = Value::from("subsystems");
+                if !server.contains_key(&key) {
+                    server.insert(key, defaults.clone());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_include_and_conf_d() {
+        let directory =
+            std::env::temp_dir().join(format!("tsample-include-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(directory.join("conf.d")).unwrap();
+        fs::create_dir_all(directory.join("teams")).unwrap();
+        let main = directory.join("config.yaml");
+        fs::write(
+            &main,
+            "include: [teams]
+default_subsystems: [{name: EventProcessingSubsystem}]
+thingworx_servers:
+  - {name: main, host: localhost, port: 8080, subsystems: []}
+",
+        )
+        .unwrap();
+        fs::write(
+            directory.join("teams/b.yaml"),
+            "thingworx_servers: [{name: b, host: localhost, port: 8080}]",
+        )
+        .unwrap();
+        fs::write(
+            directory.join("teams/a.yml"),
+            "thingworx_servers: [{name: a, host: localhost, port: 8080}]",
+        )
+        .unwrap();
+        fs::write(directory.join("teams/notes.txt"), "not a configuration").unwrap();
+        fs::write(
+            directory.join("conf.d/exports.yaml"),
+            "export_to_influxdb: {enabled: false, server_name: localhost}",
+        )
+        .unwrap();
+
+        let file_name = main.to_str().unwrap();
+        let document = load(file_name).unwrap();
+        let servers = document[SERVERS_KEY].as_sequence().unwrap();
+        let names: Vec<&str> = servers
+            .iter()
+            .map(|s| s["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, vec!["main", "a", "b"]);
+        // an explicit empty list is kept, the others inherit the defaults.
+        assert!(servers[0]["subsystems"].as_sequence().unwrap().is_empty());
+        assert_eq!(
+            servers[2]["subsystems"][0]["name"].as_str(),
+            Some("EventProcessingSubsystem")
+        );
+        assert_eq!(
+            document["export_to_influxdb"]["enabled"].as_bool(),
+            Some(false)
+        );
+        assert!(document.get(INCLUDE_KEY).is_none());
+        assert_eq!(watched_files(file_name).len(), 4);
+
+        let files = watched_files(file_name)
+            .into_iter()
+            .map(|path| ConfigFile {
+                document: read(&path).unwrap(),
+                path,
+            })
+            .collect();
+        let (_, origins) = merge(files).unwrap();
+        assert_eq!(
+            origins.locate("thingworx_servers[2].port"),
+            (2, "thingworx_servers[0].port".to_string())
+        );
+        assert_eq!(
+            origins.locate("export_to_influxdb.enabled"),
+            (3, "export_to_influxdb.enabled".to_string())
+        );
+
+        fs::write(
+            directory.join("conf.d/twice.yaml"),
+            "export_to_influxdb: {enabled: true, server_name: localhost}",
+        )
+        .unwrap();
+        let error = load(file_name).unwrap_err().to_string();
+        assert!(error.starts_with("export_to_influxdb is defined in both"));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
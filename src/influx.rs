@@ -1,173 +1,418 @@
-use std::{
-    fs,
-    io::Write,
-    path::{Path, PathBuf},
-};
+use std::time::{Duration, Instant};
 
+use crate::lineprotocol::{self, is_valid_precision};
+use crate::sink::{FlushError, Sink};
 use crate::spec::WriteSpec;
-use chrono::{DateTime, Datelike, NaiveDate, Utc};
-use influxdb::WriteQuery;
-use influxdb::{Client, InfluxDbWriteable};
-use tokio::sync::mpsc::{Receiver, Sender};
-
-use crate::testconfig::{ExportToFile, ExportToInfluxDB};
-
-pub async fn launch_influx_service(
-    influx_config: &ExportToInfluxDB,
-    mut receiver: Receiver<Vec<WriteSpec>>,
-    file_config: Option<ExportToFile>,
-    prom_sender: Option<Sender<Vec<WriteSpec>>>,
-) -> anyhow::Result<()> {
-    let enabled = influx_config.enabled;
-    log::info!("influxdb service enabled:{}", enabled);
-    let url = format!(
-        "{}://{}:{}",
-        influx_config.protocol, influx_config.server_name, influx_config.port
-    );
-    let client = Client::new(&url, influx_config.database.clone());
-    let client = match (
-        influx_config.username.as_ref(),
-        influx_config.password.as_ref(),
-    ) {
-        (Some(username), Some(password)) => client.with_auth(username.clone(), password.clone()),
-        _ => {
-            log::debug!("no username and password");
-            client
+use crate::spool::{self, Spool};
+use async_trait::async_trait;
+use reqwest::{
+    header::{AUTHORIZATION, CONTENT_TYPE},
+    StatusCode,
+};
+use url::Url;
+
+use crate::testconfig::ExportToInfluxDB;
+
+/// Collects points as line protocol and writes them with one request per batch.
+/// The 1.x API (/write) and the 2.x API (/api/v2/write, also served by InfluxDB 3.x)
+/// differ in the URL, the authentication and the encoding of unsigned integers.
+pub struct InfluxWriter {
+    client: reqwest::Client,
+    url: Url,
+    token: Option<String>,
+    basic_auth: Option<(String, String)>,
+    precision: String,
+    // only the 2.x API accepts unsigned integers.
+    unsigned: bool,
+    batch_size: usize,
+    flush_interval: Duration,
+    buffer: String,
+    points: usize,
+    last_flush: Instant,
+    spool: Option<Spool>,
+}
+
+#[async_trait]
+impl Sink for InfluxWriter {
+    async fn write(&mut self, write_specs: &[WriteSpec]) -> anyhow::Result<usize> {
+        self.push(write_specs);
+        if self.is_full() {
+            return Ok(self.flush().await?);
         }
-    };
+        Ok(0)
+    }
 
-    let sender = match file_config {
-        None => None,
-        Some(file_config) => {
-            if file_config.enabled {
-                let (sender, receiver) = tokio::sync::mpsc::channel(1000);
-                tokio::spawn(async move {
-                    log::info!("Export to file service launched.");
-                    match launch_file_service(file_config, receiver).await {
-                        Ok(_) => {
-                            log::info!("file service finished.");
-                        }
-                        Err(e) => {
-                            log::error!("file service error:{:?}", e);
-                        }
-                    }
-                });
-                Some(sender)
-            } else {
-                None
+    async fn tick(&mut self) -> anyhow::Result<usize> {
+        let written = if self.is_due() {
+            self.flush().await?
+        } else {
+            0
+        };
+        match self.replay().await {
+            Ok(replayed) => Ok(written + replayed),
+            Err(mut e) => {
+                e.written += written;
+                Err(e.into())
             }
         }
-    };
-    loop {
-        match receiver.recv().await {
-            None => break,
-            Some(write_specs) => {
-                if let Some(ref sender) = prom_sender {
-                    match sender.send(write_specs.clone()).await {
-                        Ok(_) => {}
-                        Err(e) => {
-                            log::error!("prometheus sender error:{:?}", e);
-                        }
-                    }
-                }
-                let mut write_query = vec![];
-                for spec in write_specs {
-                    let WriteSpec {
-                        fields,
-                        tags,
-                        measurement,
-                        timestamp,
-                    } = spec;
-                    let mut one_query = timestamp.into_query(measurement);
-
-                    for field in fields {
-                        one_query = one_query.add_field(field.0, field.1);
-                    }
-                    for tag in tags {
-                        one_query = one_query.add_tag(tag.0, tag.1);
-                    }
-                    write_query.push(one_query);
-                }
+    }
 
-                if let Some(ref sender) = sender {
-                    // 99.99% chance InfluxDB will be enabled, so, doesn't matter to clone the data.
-                    let file_query = write_query.clone();
-                    let _ = sender.send(file_query).await;
+    fn tick_interval(&self) -> Option<Duration> {
+        Some(self.flush_interval)
+    }
+
+    async fn close(&mut self) -> anyhow::Result<usize> {
+        Ok(self.flush().await?)
+    }
+}
+
+pub(crate) enum WriteError {
+    // the sink is not reachable or not healthy, the batch can be written later.
+    Retry(anyhow::Error),
+    // the sink refused the batch itself, writing it again won't help.
+    Reject(anyhow::Error),
+}
+
+impl InfluxWriter {
+    pub fn new(influx_config: &ExportToInfluxDB) -> anyhow::Result<Self> {
+        log::info!(
+            "influxdb service enabled, api version:{}",
+            influx_config.api_version
+        );
+        if !is_valid_precision(&influx_config.precision) {
+            return Err(anyhow::anyhow!(
+                "unsupported influxdb precision:{}, valid values are ns, us, ms and s",
+                influx_config.precision
+            ));
+        }
+        let precision = influx_config.precision.clone();
+        let base = format!(
+            "{}://{}:{}",
+            influx_config.protocol, influx_config.server_name, influx_config.port
+        );
+        let (url, token, basic_auth) = if influx_config.is_v2() {
+            let mut url = Url::parse(&format!("{}/api/v2/write", base))?;
+            {
+                let mut pairs = url.query_pairs_mut();
+                if let Some(ref org) = influx_config.org {
+                    pairs.append_pair("org", org);
                 }
-                if enabled {
-                    for query in write_query {
-                        let _ = client.query(query).await;
-                    }
+                pairs.append_pair("bucket", influx_config.get_bucket());
+                pairs.append_pair("precision", &precision);
+            }
+            if influx_config.token.is_none() {
+                log::warn!("no token configured for influxdb api version 2");
+            }
+            (url, influx_config.token.clone(), None)
+        } else {
+            let mut url = Url::parse(&format!("{}/write", base))?;
+            url.query_pairs_mut()
+                .append_pair("db", &influx_config.database)
+                // the 1.x API names microseconds "u".
+                .append_pair(
+                    "precision",
+                    if precision == "us" { "u" } else { &precision },
+                );
+            let basic_auth = match (
+                influx_config.username.as_ref(),
+                influx_config.password.as_ref(),
+            ) {
+                (Some(username), Some(password)) => Some((username.clone(), password.clone())),
+                _ => {
+                    log::debug!("no username and password");
+                    None
                 }
+            };
+            (url, None, basic_auth)
+        };
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(20))
+            .build()?;
+        let spool = match influx_config.spool {
+            Some(ref spool_config) => Some(Spool::open(spool_config)?),
+            None => None,
+        };
+
+        Ok(InfluxWriter {
+            client,
+            url,
+            token,
+            basic_auth,
+            precision,
+            unsigned: influx_config.is_v2(),
+            batch_size: influx_config.batch_size.max(1),
+            flush_interval: Duration::from_millis(influx_config.flush_interval.max(1)),
+            buffer: String::new(),
+            points: 0,
+            last_flush: Instant::now(),
+            spool,
+        })
+    }
+
+    fn push(&mut self, write_specs: &[WriteSpec]) {
+        for spec in write_specs {
+            if let Some(line) = lineprotocol::encode(spec, &self.precision, self.unsigned) {
+                self.buffer.push_str(&line);
+                self.buffer.push('\n');
+                self.points += 1;
             }
         }
     }
 
-    Ok(())
-}
+    fn is_full(&self) -> bool {
+        self.points >= self.batch_size
+    }
 
-pub async fn launch_file_service(
-    file_config: ExportToFile,
-    mut receiver: Receiver<Vec<WriteQuery>>,
-) -> anyhow::Result<()> {
-    let path = Path::new(&file_config.directory);
-    if !(path.is_dir() && path.exists()) {
-        if file_config.auto_create_folder {
-            std::fs::create_dir_all(&file_config.directory)?;
-        } else {
-            return Err(anyhow::anyhow!("directory not exist"));
+    fn is_due(&self) -> bool {
+        self.points > 0 && self.last_flush.elapsed() >= self.flush_interval
+    }
+
+    /// Writes the buffered points, returns the number of points written.
+    async fn flush(&mut self) -> Result<usize, FlushError> {
+        self.last_flush = Instant::now();
+        if self.points == 0 {
+            return Ok(0);
+        }
+        let body = std::mem::take(&mut self.buffer);
+        let points = std::mem::replace(&mut self.points, 0);
+
+        // keep the order of the points: nothing is written directly while older batches are waiting.
+        if let Some(ref mut spool) = self.spool {
+            if !spool.is_empty() {
+                self.spool_batch(&body, points)?;
+                return self.replay().await;
+            }
+        }
+        match self.send(&body).await {
+            Ok(_) => {
+                log::debug!("{} point(s) written to influxdb", points);
+                Ok(points)
+            }
+            Err(WriteError::Retry(e)) if self.spool.is_some() => {
+                self.spool_batch(&body, points)?;
+                if let Some(ref mut spool) = self.spool {
+                    spool.record_failure();
+                }
+                Err(FlushError::new(
+                    0,
+                    e.context(format!("influxdb write error, {} point(s) spooled", points)),
+                ))
+            }
+            Err(WriteError::Retry(e)) | Err(WriteError::Reject(e)) => {
+                Err(FlushError::new(points, e.context("influxdb write error")))
+            }
         }
     }
-    let now = Utc::now();
-    let mut baseline = get_datetime(now);
-    let mut file_name = get_filename(now);
-
-    let export_file_base = PathBuf::from(&file_config.directory);
-    let mut export_file = export_file_base.clone();
-    export_file.push(file_name.clone());
-    let mut file = fs::OpenOptions::new()
-        .write(true)
-        .append(true)
-        .create(true)
-        .open(export_file)?;
-
-    loop {
-        match receiver.recv().await {
-            None => break,
-            Some(write_query) => {
-                // check file name should be changed or not.
-                let now = Utc::now();
-                let new_base = get_datetime(now);
-                if new_base != baseline {
-                    baseline = new_base;
-                    file_name = get_filename(now);
-
-                    export_file = export_file_base.clone();
-                    export_file.push(file_name.clone());
-                    file = fs::OpenOptions::new()
-                        .write(true)
-                        .append(true)
-                        .create(true)
-                        .open(export_file)?;
+
+    /// Writes the spooled batches in order, until the spool is empty or a write fails.
+    /// Returns the number of points written, the rejected batches are dropped.
+    async fn replay(&mut self) -> Result<usize, FlushError> {
+        let (mut written, mut lost, mut rejected) = (0, 0, None);
+        while let Some((body, points)) = self.next_spooled() {
+            let result = self.send(&body).await;
+            let spool = match self.spool {
+                Some(ref mut spool) => spool,
+                None => break,
+            };
+            match result {
+                Ok(_) => {
+                    written += points;
+                    spool.pop_front();
+                    spool.record_success();
+                    log::info!(
+                        "{} spooled point(s) written to influxdb, pending batch(es):{}, total replayed:{}, total dropped:{}",
+                        points,
+                        spool.len(),
+                        spool::REPLAYED_POINTS.get(),
+                        spool::DROPPED_POINTS.get()
+                    );
+                }
+                Err(WriteError::Reject(e)) => {
+                    spool.drop_front();
+                    lost += points;
+                    rejected = Some(e.context("influxdb rejected spooled point(s)"));
                 }
-                for query in write_query {
-                    let content = format!("{},{:?}\n", now, query);
-                    file.write_all(content.as_bytes())?;
+                Err(WriteError::Retry(e)) => {
+                    log::debug!("influxdb is still not available:{:?}", e);
+                    spool.record_failure();
+                    break;
                 }
             }
         }
+        match rejected {
+            Some(error) => Err(FlushError {
+                written,
+                lost,
+                error,
+            }),
+            None => Ok(written),
+        }
+    }
+
+    // the oldest spooled batch, once the retry delay has passed.
+    fn next_spooled(&mut self) -> Option<(String, usize)> {
+        let spool = self.spool.as_mut()?;
+        spool.expire();
+        if !spool.is_ready() {
+            return None;
+        }
+        spool.front()
+    }
+
+    fn spool_batch(&mut self, body: &str, points: usize) -> Result<(), FlushError> {
+        if let Some(ref mut spool) = self.spool {
+            if let Err(e) = spool.push(body, points) {
+                return Err(FlushError::new(
+                    points,
+                    e.context(format!("failed to spool {} point(s)", points)),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    async fn send(&self, body: &str) -> Result<(), WriteError> {
+        let mut request = self
+            .client
+            .post(self.url.clone())
+            .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+            .body(body.to_owned());
+        if let Some(ref token) = self.token {
+            request = request.header(AUTHORIZATION, format!("Token {}", token));
+        }
+        if let Some((ref username, ref password)) = self.basic_auth {
+            request = request.basic_auth(username, Some(password));
+        }
+        let res = match request.send().await {
+            Ok(res) => res,
+            Err(e) => return Err(WriteError::Retry(e.into())),
+        };
+        if !res.status().is_success() {
+            let status = res.status();
+            let text = res.text().await.unwrap_or_default();
+            let e = anyhow::anyhow!(
+                "influxdb write failed, status:{}, response:{}",
+                status,
+                text
+            );
+            if is_rejected(status) {
+                return Err(WriteError::Reject(e));
+            }
+            return Err(WriteError::Retry(e));
+        }
+        Ok(())
     }
-    Ok(())
 }
 
-fn get_datetime(now: DateTime<Utc>) -> u32 {
-    let newtime = NaiveDate::from_ymd_opt(now.year(), now.month(), now.day())
-        .unwrap()
-        .and_hms_opt(0, 0, 0)
-        .unwrap();
-    newtime.timestamp() as u32
+// a client error other than the authentication and the rate limit means the batch itself is
+// not accepted (400 for the line protocol, 413 too large, 422 outside the retention...),
+// writing it again won't help.
+fn is_rejected(status: StatusCode) -> bool {
+    status.is_client_error()
+        && !matches!(
+            status,
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN | StatusCode::TOO_MANY_REQUESTS
+        )
 }
 
-fn get_filename(now: DateTime<Utc>) -> String {
-    format!("metrics-{}-{}-{}.txt", now.year(), now.month(), now.day())
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use influxdb::{Timestamp, Type};
+    use std::sync::{Arc, Mutex};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    // answers the requests with these statuses, and records their bodies.
+    async fn mock_influxdb(statuses: Vec<u16>) -> (u16, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let bodies = Arc::new(Mutex::new(vec![]));
+        let received = bodies.clone();
+        tokio::spawn(async move {
+            for status in statuses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = vec![];
+                let mut buffer = [0u8; 4096];
+                let body = loop {
+                    let read = stream.read(&mut buffer).await.unwrap();
+                    request.extend_from_slice(&buffer[..read]);
+                    let text = String::from_utf8_lossy(&request).to_string();
+                    if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                        let length = head
+                            .lines()
+                            .find_map(|line| {
+                                line.to_lowercase()
+                                    .strip_prefix("content-length: ")
+                                    .map(|l| l.trim().parse::<usize>().unwrap())
+                            })
+                            .unwrap_or(0);
+                        if body.len() >= length {
+                            break body.to_string();
+                        }
+                    }
+                };
+                received.lock().unwrap().push(body);
+                let response = format!(
+                    "HTTP/1.1 {} X\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                    status
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        (port, bodies)
+    }
+
+    #[test]
+    fn test_is_rejected() {
+        assert!(is_rejected(StatusCode::BAD_REQUEST));
+        assert!(is_rejected(StatusCode::PAYLOAD_TOO_LARGE));
+        assert!(is_rejected(StatusCode::UNPROCESSABLE_ENTITY));
+        assert!(!is_rejected(StatusCode::UNAUTHORIZED));
+        assert!(!is_rejected(StatusCode::FORBIDDEN));
+        assert!(!is_rejected(StatusCode::TOO_MANY_REQUESTS));
+        assert!(!is_rejected(StatusCode::SERVICE_UNAVAILABLE));
+    }
+
+    #[tokio::test]
+    async fn test_rejected_batch_leaves_the_spool() {
+        let (port, bodies) = mock_influxdb(vec![503, 413, 204]).await;
+        let directory =
+            std::env::temp_dir().join(format!("tsample-influx-reject-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        let config: ExportToInfluxDB = serde_yaml::from_str(&format!(
+            "{{enabled: true, server_name: 127.0.0.1, port: {}, batch_size: 1, \
+             spool: {{directory: '{}', retry_initial_backoff: 0}}}}",
+            port,
+            directory.display()
+        ))
+        .unwrap();
+        let mut writer = InfluxWriter::new(&config).unwrap();
+        let spec = |value: i64| {
+            WriteSpec::new(Timestamp::Seconds(1), "m").add_field("v", Type::SignedInteger(value))
+        };
+
+        let flush_error = |result: anyhow::Result<usize>| {
+            let error = result.unwrap_err().downcast::<FlushError>().unwrap();
+            (error.written, error.lost)
+        };
+
+        // influxdb is down, the first batch is spooled.
+        assert_eq!(flush_error(writer.write(&[spec(1)]).await), (0, 0));
+        assert_eq!(writer.spool.as_ref().unwrap().len(), 1);
+        // skips the retry delay.
+        writer.spool.as_mut().unwrap().record_success();
+        // the first batch is too large: it's dropped instead of blocking the second one.
+        assert_eq!(flush_error(writer.write(&[spec(2)]).await), (1, 1));
+        assert!(writer.spool.as_ref().unwrap().is_empty());
+
+        let bodies = bodies.lock().unwrap().clone();
+        assert_eq!(bodies.len(), 3);
+        assert!(bodies[0].starts_with("m v=1i"));
+        assert!(bodies[1].starts_with("m v=1i"));
+        assert!(bodies[2].starts_with("m v=2i"));
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
 }
@@ -1,23 +1,23 @@
 use crate::spec::WriteSpec as WriteQuery;
 use crate::{
+    auth::{headers_for, invalidate},
+    breaker::run_query,
+    httpclient::{acquire, client_for},
     payload::{MBeansAttributeInfo, QueryMBeansTree},
+    reload::{sleep_or_reload, ConfigReceiver},
     tabular::parse_tabular_data,
-    testconfig::{JmxMetric, SubSystem, TestConfig, ThingworxServer},
-    twxquery::construct_headers,
+    selfmetrics::Scrape,
+    testconfig::{JmxMetric, Schedule, SubSystem, ThingworxServer},
 };
 use chrono::offset::Utc;
 use chrono::DateTime;
 use influxdb::Timestamp;
 use lazy_static::lazy_static;
 use regex::Regex;
-use reqwest::{header::HeaderMap, Client};
+use reqwest::{header::HeaderMap, Client, StatusCode};
 
 use std::{
-    collections::HashMap,
-    sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
-    },
+    collections::{HashMap, HashSet},
     time::SystemTime,
 };
 
@@ -28,21 +28,52 @@ pub type JmxObjectNameList = Vec<(
     Vec<String>,    // list of query result to filter. default is empty, which means no filter
 )>;
 pub async fn refresh_jmx(
-    tc: TestConfig,
+    mut config: ConfigReceiver,
     mut writer: evmap::WriteHandle<String, JmxObjectNameList>,
-    sleeping: Arc<AtomicBool>,
 ) -> anyhow::Result<()> {
+    // the servers in the map, their entry is removed with their jmx_metrics block.
+    let mut written: HashSet<String> = HashSet::new();
     loop {
+        let tc = config.borrow_and_update().clone();
+        let configured: HashSet<String> = tc
+            .thingworx_servers
+            .iter()
+            .filter(|server| server.jmx_metrics.is_some())
+            .map(|server| server.name.clone())
+            .collect();
+        for name in written.difference(&configured) {
+            log::info!("JMX MBeans of server:{} removed", name);
+            writer.empty(name.clone());
+        }
+        writer.refresh();
+        written = configured;
+
         for server in tc.thingworx_servers.iter() {
             if let Some(ref jmx_configs) = server.jmx_metrics {
-                let headers = construct_headers(&server.app_key);
                 let url = server.get_query_mbeanstree_url();
-                let client = reqwest::Client::builder()
-                    .timeout(std::time::Duration::from_secs(20))
-                    .danger_accept_invalid_certs(true)
-                    .build()?;
+                let client = match client_for(server, tc.query_time_out) {
+                    Ok(client) => client,
+                    Err(e) => {
+                        log::error!("JMX MBeans query service error:{:?}", e);
+                        continue;
+                    }
+                };
+                let headers = match headers_for(server).await {
+                    Ok(headers) => headers,
+                    Err(e) => {
+                        log::error!("JMX MBeans query service error:{:?}", e);
+                        continue;
+                    }
+                };
                 log::debug!("JMX MBeans query url:{},jmx_configs:{:?}", url, jmx_configs);
-                let res = match client.post(url).headers(headers.clone()).send().await {
+                let _permit = acquire(&server.name).await;
+                let res = match client
+                    .post(url)
+                    .headers(headers.clone())
+                    .timeout(std::time::Duration::from_secs(20))
+                    .send()
+                    .await
+                {
                     Ok(res) => res,
                     Err(e) => {
                         log::error!("JMX MBeans query service error:{:?}", e);
@@ -52,6 +83,9 @@ pub async fn refresh_jmx(
 
                 if !res.status().is_success() {
                     log::error!("JMX MBeans query :{} failed", server.name);
+                    if res.status() == StatusCode::UNAUTHORIZED {
+                        invalidate(server, &headers).await;
+                    }
                     continue;
                 }
 
@@ -73,6 +107,7 @@ pub async fn refresh_jmx(
                     object_name_pattern,
                     name_label_alternative,
                     metrics,
+                    ..
                 } in jmx_configs.iter()
                 {
                     let mut object_names = vec![];
@@ -100,9 +135,11 @@ pub async fn refresh_jmx(
             }
         }
         writer.refresh();
-        sleeping.store(true, Ordering::SeqCst);
-        tokio::time::sleep(std::time::Duration::from_secs(tc.refresh_server_interval)).await;
-        sleeping.store(false, Ordering::SeqCst);
+        sleep_or_reload(
+            &mut config,
+            std::time::Duration::from_secs(tc.refresh_server_interval),
+        )
+        .await;
     }
     // Ok(())
 }
@@ -117,12 +154,8 @@ pub async fn repeated_jmx_query(
     query_timeout: u64,
 ) -> anyhow::Result<()> {
     let url = server.get_mbean_attributeinfo_url();
-    let client = reqwest::Client::builder()
-        .timeout(std::time::Duration::from_secs(query_timeout))
-        .danger_accept_invalid_certs(true)
-        .build()?;
+    let client = client_for(server, query_timeout)?;
     log::debug!("JMX MBeans query url:{},metrics:{}", url, metrics.join(","));
-    let headers = construct_headers(&server.app_key);
     for object_name in object_name_list.iter() {
         let jmx_subsystem = SubSystem {
             name: measurement.clone(),
@@ -130,6 +163,7 @@ pub async fn repeated_jmx_query(
             enabled: true,
             sanitize: false,
             split_desc_asprefix: false,
+            schedule: Schedule::default(),
         };
 
         let payload = serde_json::json!({
@@ -148,30 +182,29 @@ pub async fn repeated_jmx_query(
         };
         additional_tags.insert("sub_name".to_string(), sub_name);
 
-        let result = match query_jmx_metrics(
-            client.clone(),
-            &url,
-            &headers,
-            payload.to_string(),
-            &jmx_subsystem,
-            &server.name,
-            Some(additional_tags),
-            name_alternative.clone(),
-        )
-        .await
-        {
-            Ok(result) => result,
-            Err(e) => {
-                log::error!("JMX MBeans query error:{:?}", e);
-                return Ok(());
+        run_query(server, "jmx", object_name, &sender, |mut scrape, headers| {
+            let client = client.clone();
+            let additional_tags = additional_tags.clone();
+            let name_alternative = name_alternative.clone();
+            let (url, jmx_subsystem) = (&url, &jmx_subsystem);
+            let payload = payload.to_string();
+            async move {
+                let result = query_jmx_metrics(
+                    client,
+                    url,
+                    &headers,
+                    payload,
+                    jmx_subsystem,
+                    &server.name,
+                    Some(additional_tags),
+                    name_alternative,
+                    &mut scrape,
+                )
+                .await;
+                (scrape, result)
             }
-        };
-        log::debug!(
-            "JMX MBeans query:{} metrics result:{}",
-            object_name,
-            result.len()
-        );
-        let _ = sender.send(result).await;
+        })
+        .await;
     }
 
     Ok(())
@@ -187,8 +220,11 @@ async fn query_jmx_metrics(
     platform: &str,
     additional_tags: Option<HashMap<String, String>>,
     name_alternative: Option<String>,
+    scrape: &mut Scrape,
 ) -> anyhow::Result<Vec<WriteQuery>> {
     let mut result = vec![];
+    let _permit = acquire(platform).await;
+    scrape.restart();
     let response_start = SystemTime::now();
 
     // if this step is error, likely the server is not responsive.
@@ -201,6 +237,7 @@ async fn query_jmx_metrics(
         .body(payload)
         .send()
         .await?;
+    scrape.response(res.status());
     if !res.status().is_success() {
         // return Err(anyhow::anyhow!("Subsystem metrics query:{} failed", url));
         log::error!(
@@ -220,6 +257,7 @@ async fn query_jmx_metrics(
                 e,
                 payload_backup
             );
+            scrape.parse_error();
             return Ok(result); // return empty result
         }
     };
@@ -0,0 +1,166 @@
+use chrono::{DateTime, Utc};
+use influxdb::{Timestamp, Type};
+
+use crate::spec::WriteSpec;
+
+// InfluxDB line protocol encoder.
+// https://docs.influxdata.com/influxdb/v2/reference/syntax/line-protocol/
+
+/// Encodes one `WriteSpec` as a single line, without the trailing newline.
+/// Returns `None` if the spec has no valid field, since InfluxDB rejects such a line.
+/// Unsigned integers are written with the `u` suffix if `unsigned` is set (InfluxDB 2.x),
+/// otherwise as signed integers, skipping the values above `i64::MAX` (InfluxDB 1.x).
+pub fn encode(spec: &WriteSpec, precision: &str, unsigned: bool) -> Option<String> {
+    let mut fields = String::new();
+    for (key, value) in spec.fields.iter() {
+        let value = match encode_field_value(value, unsigned) {
+            Some(value) => value,
+            None => {
+                if let Type::UnsignedInteger(value) = value {
+                    log::warn!(
+                        "{}.{}:{} is too large for a signed integer, field skipped",
+                        spec.measurement,
+                        key,
+                        value
+                    );
+                }
+                continue;
+            }
+        };
+        if !fields.is_empty() {
+            fields.push(',');
+        }
+        escape_into(&mut fields, key, &[',', '=', ' ']);
+        fields.push('=');
+        fields.push_str(&value);
+    }
+    if fields.is_empty() {
+        return None;
+    }
+
+    let mut line = String::with_capacity(spec.measurement.len() + fields.len() + 64);
+    escape_into(&mut line, &spec.measurement, &[',', ' ']);
+    for (key, value) in spec.tags.iter() {
+        let value = value.to_string();
+        // empty tag values are not allowed in line protocol.
+        if value.is_empty() {
+            continue;
+        }
+        line.push(',');
+        escape_into(&mut line, key, &[',', '=', ' ']);
+        line.push('=');
+        escape_into(&mut line, &value, &[',', '=', ' ']);
+    }
+    line.push(' ');
+    line.push_str(&fields);
+    line.push(' ');
+    line.push_str(&timestamp_in(spec.timestamp, precision).to_string());
+    Some(line)
+}
+
+/// Converts the timestamp into an integer of the given precision ("ns", "us", "ms" or "s").
+pub fn timestamp_in(timestamp: Timestamp, precision: &str) -> i64 {
+    let datetime: DateTime<Utc> = timestamp.into();
+    match precision {
+        "ns" => datetime.timestamp_nanos(),
+        "us" => datetime.timestamp_micros(),
+        "s" => datetime.timestamp(),
+        _ => datetime.timestamp_millis(),
+    }
+}
+
+pub fn is_valid_precision(precision: &str) -> bool {
+    matches!(precision, "ns" | "us" | "ms" | "s")
+}
+
+fn encode_field_value(value: &Type, unsigned: bool) -> Option<String> {
+    match value {
+        Type::Boolean(value) => Some(value.to_string()),
+        // NaN and infinity can't be represented in line protocol.
+        Type::Float(value) if !value.is_finite() => None,
+        Type::Float(value) => Some(value.to_string()),
+        Type::SignedInteger(value) => Some(format!("{}i", value)),
+        Type::UnsignedInteger(value) if unsigned => Some(format!("{}u", value)),
+        Type::UnsignedInteger(value) => i64::try_from(*value).ok().map(|v| format!("{}i", v)),
+        Type::Text(value) => {
+            let mut text = String::with_capacity(value.len() + 2);
+            text.push('"');
+            escape_into(&mut text, value, &['"', '\\']);
+            text.push('"');
+            Some(text)
+        }
+    }
+}
+
+fn escape_into(target: &mut String, source: &str, special: &[char]) {
+    for c in source.chars() {
+        if special.contains(&c) {
+            target.push('\\');
+        }
+        // a new line would end the point.
+        if c == '\n' {
+            target.push_str("\\n");
+            continue;
+        }
+        target.push(c);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_spec() {
+        let spec = WriteSpec::new(Timestamp::Milliseconds(1683600000123), "Value Stream")
+            .add_tag("Provider", Type::Text("Postgres,Main".to_string()))
+            .add_tag("Platform", Type::Text("platform1".to_string()))
+            .add_field("queue size", Type::Float(1.5))
+            .add_field("ResponseTime", Type::SignedInteger(42))
+            .add_field("Valid", Type::Boolean(true))
+            .add_field("desc", Type::Text(r#"say "hi" \o/"#.to_string()));
+
+        assert_eq!(
+            encode(&spec, "ms", true).unwrap(),
+            r#"Value\ Stream,Provider=Postgres\,Main,Platform=platform1 queue\ size=1.5,ResponseTime=42i,Valid=true,desc="say \"hi\" \\o/" 1683600000123"#
+        );
+    }
+
+    #[test]
+    fn test_encode_precision() {
+        let spec = WriteSpec::new(Timestamp::Milliseconds(1683600000123), "m")
+            .add_field("v", Type::SignedInteger(1));
+        assert_eq!(encode(&spec, "s", true).unwrap(), "m v=1i 1683600000");
+        assert_eq!(
+            encode(&spec, "us", true).unwrap(),
+            "m v=1i 1683600000123000"
+        );
+        assert_eq!(
+            encode(&spec, "ns", true).unwrap(),
+            "m v=1i 1683600000123000000"
+        );
+    }
+
+    #[test]
+    fn test_encode_skips_invalid() {
+        let spec = WriteSpec::new(Timestamp::Milliseconds(0), "m")
+            .add_tag("empty", Type::Text("".to_string()))
+            .add_field("nan", Type::Float(f64::NAN));
+        assert!(encode(&spec, "ms", true).is_none());
+
+        let spec = spec.add_field("v", Type::Float(2.0));
+        assert_eq!(encode(&spec, "ms", true).unwrap(), "m v=2 0");
+    }
+
+    #[test]
+    fn test_encode_unsigned() {
+        let spec = WriteSpec::new(Timestamp::Milliseconds(0), "m")
+            .add_field("small", Type::UnsignedInteger(7))
+            .add_field("large", Type::UnsignedInteger(u64::MAX));
+        assert_eq!(
+            encode(&spec, "ms", true).unwrap(),
+            "m small=7u,large=18446744073709551615u 0"
+        );
+        assert_eq!(encode(&spec, "ms", false).unwrap(), "m small=7i 0");
+    }
+}
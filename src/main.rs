@@ -1,16 +1,32 @@
 mod app;
+mod auth;
+mod breaker;
+mod check;
+mod fileexport;
+mod graphite;
+mod httpclient;
+mod include;
 mod influx;
 mod jmxquery;
+mod lineprotocol;
+mod otlp;
 mod payload;
 mod prometheus;
+mod reload;
+mod remotewrite;
+mod scheduler;
+mod secrets;
+mod selfmetrics;
+mod sink;
 mod spec;
+mod spool;
+mod statsd;
 mod tabular;
 mod testconfig;
 mod twxquery;
 
 use std::{
     io::Write,
-    process,
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
@@ -53,7 +69,8 @@ async fn main() -> anyhow::Result<()> {
                 .long("config")
                 .value_name("CONFIG_FILE")
                 .help("Configuration file name, it should be a YAML file.")
-                .takes_value(true),
+                .takes_value(true)
+                .global(true),
         )
         .arg(
             Arg::new("export")
@@ -70,12 +87,23 @@ async fn main() -> anyhow::Result<()> {
                 .value_name("FLATTEN_FILE")
                 .requires("config"),
         )
+        .subcommand(
+            Command::new("check")
+                .about("Validate the configuration file and report every problem with its line."),
+        )
         .get_matches();
     let config_file = match matches.value_of("config") {
         Some(value) => value.to_string(),
         None => env::var("TSAMPLE_CONFIG").unwrap_or_else(|_| "./config.yaml".to_string()),
     };
 
+    if matches.subcommand_matches("check").is_some() {
+        if !check::check_file(&config_file)? {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
     if matches.is_present("export") {
         let mut output = File::create(&config_file)?;
         write!(output, "{}", SAMPLE_CONFIG)?;
@@ -86,10 +114,12 @@ async fn main() -> anyhow::Result<()> {
     if let Some(flatten_file) = matches.value_of("flatten") {
         let output = File::create(flatten_file)?;
         let config = TestConfig::load_from_file(&config_file)?;
-        serde_yaml::to_writer(output, &config)?;
+        let mut document = serde_yaml::to_value(&config)?;
+        secrets::redact(&mut document);
+        serde_yaml::to_writer(output, &document)?;
 
         log::info!(
-            "Flattened configuration file is exported to {}",
+            "Flattened configuration file is exported to {}, the secrets are redacted",
             flatten_file
         );
         return Ok(());
@@ -97,33 +127,16 @@ async fn main() -> anyhow::Result<()> {
     let testconfig: TestConfig = TestConfig::load_from_file(&config_file)?;
 
     let running = Arc::new(AtomicBool::new(true));
-    let main_query_sleeping = Arc::new(AtomicBool::new(false));
-    let cxs_refresh_sleeping = Arc::new(AtomicBool::new(false));
-    let jmx_refresh_sleeping = Arc::new(AtomicBool::new(false));
 
+    // the queries stop within a second, then the exports write what they have.
     let r = running.clone();
-    let m = main_query_sleeping.clone();
-    let c = cxs_refresh_sleeping.clone();
-    let j = jmx_refresh_sleeping.clone();
-
     ctrlc::set_handler(move || {
         log::info!("Received Ctrl-C from console.");
         r.store(false, Ordering::SeqCst);
-        if m.load(Ordering::SeqCst) || c.load(Ordering::SeqCst) || j.load(Ordering::SeqCst) {
-            log::warn!("Quit from sleeping...");
-            process::exit(0);
-        }
     })
     .expect("Error setting Ctrl-C handler");
 
-    app::run_app(
-        testconfig,
-        running,
-        main_query_sleeping,
-        cxs_refresh_sleeping,
-        jmx_refresh_sleeping,
-    )
-    .await?;
+    app::run_app(config_file, testconfig, running).await?;
     // let sleep = match testconfig.testmachine.sampling_cycle_inseconds {
     //     Some(seconds) => seconds * 1000,
     //     None => 120 * 1000,
@@ -0,0 +1,510 @@
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::influx::WriteError;
+use crate::lineprotocol::timestamp_in;
+use crate::prometheus::gauge_value;
+use crate::sink::{FlushError, Sink};
+use crate::spec::WriteSpec;
+use crate::testconfig::ExportToOtlp;
+use async_trait::async_trait;
+use prost::Message;
+use reqwest::header::{CONTENT_TYPE, USER_AGENT};
+
+// OTLP metrics messages (opentelemetry/proto/collector/metrics/v1), only the fields used by tsample.
+// https://github.com/open-telemetry/opentelemetry-proto/blob/main/opentelemetry/proto/metrics/v1/metrics.proto
+// The `oneof` fields are declared as optional fields with the same tags, which is the same on the wire
+// as long as only one of them is set.
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ExportMetricsServiceRequest {
+    #[prost(message, repeated, tag = "1")]
+    pub resource_metrics: Vec<ResourceMetrics>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ResourceMetrics {
+    #[prost(message, optional, tag = "1")]
+    pub resource: Option<Resource>,
+    #[prost(message, repeated, tag = "2")]
+    pub scope_metrics: Vec<ScopeMetrics>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Resource {
+    #[prost(message, repeated, tag = "1")]
+    pub attributes: Vec<KeyValue>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ScopeMetrics {
+    #[prost(message, optional, tag = "1")]
+    pub scope: Option<InstrumentationScope>,
+    #[prost(message, repeated, tag = "2")]
+    pub metrics: Vec<Metric>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct InstrumentationScope {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub version: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Metric {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub description: String,
+    #[prost(string, tag = "3")]
+    pub unit: String,
+    // oneof data
+    #[prost(message, optional, tag = "5")]
+    pub gauge: Option<Gauge>,
+    #[prost(message, optional, tag = "9")]
+    pub histogram: Option<Histogram>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Gauge {
+    #[prost(message, repeated, tag = "1")]
+    pub data_points: Vec<NumberDataPoint>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Histogram {
+    #[prost(message, repeated, tag = "1")]
+    pub data_points: Vec<HistogramDataPoint>,
+    #[prost(int32, tag = "2")]
+    pub aggregation_temporality: i32,
+}
+
+// AggregationTemporality
+pub const AGGREGATION_TEMPORALITY_DELTA: i32 = 1;
+
+#[derive(Clone, PartialEq, Message)]
+pub struct NumberDataPoint {
+    #[prost(message, repeated, tag = "7")]
+    pub attributes: Vec<KeyValue>,
+    #[prost(fixed64, tag = "3")]
+    pub time_unix_nano: u64,
+    // oneof value
+    #[prost(double, optional, tag = "4")]
+    pub as_double: Option<f64>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct HistogramDataPoint {
+    #[prost(message, repeated, tag = "9")]
+    pub attributes: Vec<KeyValue>,
+    #[prost(fixed64, tag = "2")]
+    pub start_time_unix_nano: u64,
+    #[prost(fixed64, tag = "3")]
+    pub time_unix_nano: u64,
+    #[prost(fixed64, tag = "4")]
+    pub count: u64,
+    #[prost(double, optional, tag = "5")]
+    pub sum: Option<f64>,
+    #[prost(fixed64, repeated, tag = "6")]
+    pub bucket_counts: Vec<u64>,
+    #[prost(double, repeated, tag = "7")]
+    pub explicit_bounds: Vec<f64>,
+    #[prost(double, optional, tag = "11")]
+    pub min: Option<f64>,
+    #[prost(double, optional, tag = "12")]
+    pub max: Option<f64>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct KeyValue {
+    #[prost(string, tag = "1")]
+    pub key: String,
+    #[prost(message, optional, tag = "2")]
+    pub value: Option<AnyValue>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct AnyValue {
+    // oneof value
+    #[prost(string, optional, tag = "1")]
+    pub string_value: Option<String>,
+}
+
+// the tag moved to the resource attributes, all the other tags are data point attributes.
+const RESOURCE_TAG: &str = "Platform";
+
+// platform, metric name and data point attributes.
+type HistogramKey = (String, String, Vec<(String, String)>);
+
+/// Builds one request from the specs collected since `start_time_unix_nano`.
+///
+/// Every numeric field becomes a gauge named `{measurement}.{field}`. `ResponseTime` becomes
+/// a `{measurement}.ResponseTime` delta histogram (milliseconds), aggregated over the batch.
+pub fn build_request(
+    specs: &[WriteSpec],
+    service_name: &str,
+    bounds: &[f64],
+    start_time_unix_nano: u64,
+    time_unix_nano: u64,
+) -> ExportMetricsServiceRequest {
+    // platform -> metric name -> metric
+    let mut platforms: BTreeMap<String, BTreeMap<String, Metric>> = BTreeMap::new();
+    // the histogram data point of each platform, metric and attributes.
+    let mut histograms: BTreeMap<HistogramKey, HistogramDataPoint> = BTreeMap::new();
+
+    for spec in specs {
+        let mut platform = String::new();
+        let mut attributes = vec![];
+        for (key, value) in spec.tags.iter() {
+            let value = value.to_string();
+            if value.is_empty() {
+                continue;
+            }
+            if key == RESOURCE_TAG {
+                platform = value;
+            } else {
+                attributes.push((key.clone(), value));
+            }
+        }
+        attributes.sort();
+
+        let metrics = platforms.entry(platform.clone()).or_default();
+        for (field, value) in spec.fields.iter() {
+            let value = match gauge_value(value) {
+                Some(value) if value.is_finite() => value,
+                _ => continue,
+            };
+            let name = metric_name(&spec.measurement, field);
+            if field == "ResponseTime" {
+                metrics.entry(name.clone()).or_insert_with(|| Metric {
+                    name: name.clone(),
+                    description: "Response time in milliseconds".to_string(),
+                    unit: "ms".to_string(),
+                    gauge: None,
+                    histogram: Some(Histogram {
+                        data_points: vec![],
+                        aggregation_temporality: AGGREGATION_TEMPORALITY_DELTA,
+                    }),
+                });
+                let point = histograms
+                    .entry((platform.clone(), name, attributes.clone()))
+                    .or_insert_with(|| HistogramDataPoint {
+                        attributes: key_values(&attributes),
+                        start_time_unix_nano,
+                        time_unix_nano,
+                        bucket_counts: vec![0; bounds.len() + 1],
+                        explicit_bounds: bounds.to_vec(),
+                        ..Default::default()
+                    });
+                observe(point, value / 1000000.0); // convert to milliseconds from nanoseconds
+                continue;
+            }
+
+            let point = NumberDataPoint {
+                attributes: key_values(&attributes),
+                time_unix_nano: timestamp_in(spec.timestamp, "ns") as u64,
+                as_double: Some(value),
+            };
+            metrics
+                .entry(name.clone())
+                .or_insert_with(|| Metric {
+                    name,
+                    description: String::new(),
+                    unit: String::new(),
+                    gauge: Some(Gauge::default()),
+                    histogram: None,
+                })
+                .gauge
+                .get_or_insert_with(Gauge::default)
+                .data_points
+                .push(point);
+        }
+    }
+    for ((platform, name, _), point) in histograms {
+        if let Some(histogram) = platforms
+            .get_mut(&platform)
+            .and_then(|metrics| metrics.get_mut(&name))
+            .and_then(|metric| metric.histogram.as_mut())
+        {
+            histogram.data_points.push(point);
+        }
+    }
+
+    let resource_metrics = platforms
+        .into_iter()
+        .filter(|(_, metrics)| !metrics.is_empty())
+        .map(|(platform, metrics)| {
+            let mut attributes = vec![("service.name".to_string(), service_name.to_string())];
+            if !platform.is_empty() {
+                attributes.push((RESOURCE_TAG.to_string(), platform));
+            }
+            ResourceMetrics {
+                resource: Some(Resource {
+                    attributes: key_values(&attributes),
+                }),
+                scope_metrics: vec![ScopeMetrics {
+                    scope: Some(InstrumentationScope {
+                        name: env!("CARGO_PKG_NAME").to_string(),
+                        version: env!("CARGO_PKG_VERSION").to_string(),
+                    }),
+                    metrics: metrics.into_values().collect(),
+                }],
+            }
+        })
+        .collect();
+    ExportMetricsServiceRequest { resource_metrics }
+}
+
+/// OTLP metric name of one field: `{measurement}.{field}`, with the invalid characters replaced.
+pub fn metric_name(measurement: &str, field: &str) -> String {
+    let mut name = String::with_capacity(measurement.len() + field.len() + 1);
+    for (i, c) in measurement
+        .chars()
+        .chain(Some('.'))
+        .chain(field.chars())
+        .enumerate()
+    {
+        let valid = c.is_ascii_alphabetic()
+            || (i > 0 && (c.is_ascii_digit() || matches!(c, '_' | '.' | '-' | '/')));
+        name.push(if valid { c } else { '_' });
+    }
+    name
+}
+
+fn observe(point: &mut HistogramDataPoint, value: f64) {
+    let bucket = point
+        .explicit_bounds
+        .iter()
+        .position(|bound| value <= *bound)
+        .unwrap_or(point.explicit_bounds.len());
+    point.bucket_counts[bucket] += 1;
+    point.count += 1;
+    point.sum = Some(point.sum.unwrap_or(0.0) + value);
+    point.min = Some(point.min.map_or(value, |min| min.min(value)));
+    point.max = Some(point.max.map_or(value, |max| max.max(value)));
+}
+
+fn key_values(attributes: &[(String, String)]) -> Vec<KeyValue> {
+    attributes
+        .iter()
+        .map(|(key, value)| KeyValue {
+            key: key.clone(),
+            value: Some(AnyValue {
+                string_value: Some(value.clone()),
+            }),
+        })
+        .collect()
+}
+
+fn now_nanos() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+pub struct OtlpWriter {
+    client: reqwest::Client,
+    endpoint: String,
+    headers: Vec<(String, String)>,
+    service_name: String,
+    bounds: Vec<f64>,
+    batch_size: usize,
+    flush_interval: Duration,
+    max_retries: u32,
+    retry_backoff: Duration,
+    specs: Vec<WriteSpec>,
+    last_flush: Instant,
+    // start of the current delta histogram period.
+    period_start: u64,
+}
+
+#[async_trait]
+impl Sink for OtlpWriter {
+    async fn write(&mut self, write_specs: &[WriteSpec]) -> anyhow::Result<usize> {
+        self.push(write_specs);
+        if self.is_full() {
+            return Ok(self.flush().await?);
+        }
+        Ok(0)
+    }
+
+    async fn tick(&mut self) -> anyhow::Result<usize> {
+        if self.is_due() {
+            return Ok(self.flush().await?);
+        }
+        Ok(0)
+    }
+
+    fn tick_interval(&self) -> Option<Duration> {
+        Some(self.flush_interval)
+    }
+
+    async fn close(&mut self) -> anyhow::Result<usize> {
+        Ok(self.flush().await?)
+    }
+}
+
+impl OtlpWriter {
+    pub fn new(config: &ExportToOtlp) -> anyhow::Result<Self> {
+        log::info!("otlp service launched, endpoint:{}", config.endpoint);
+        url::Url::parse(&config.endpoint)?;
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(config.timeout.max(1)))
+            .build()?;
+        let mut bounds = config.response_time_bucket_bin.clone();
+        bounds.sort_by(|a, b| a.total_cmp(b));
+
+        Ok(OtlpWriter {
+            client,
+            endpoint: config.endpoint.clone(),
+            headers: config
+                .headers
+                .iter()
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect(),
+            service_name: config.service_name.clone(),
+            bounds,
+            batch_size: config.batch_size.max(1),
+            flush_interval: Duration::from_millis(config.flush_interval.max(1)),
+            max_retries: config.max_retries,
+            retry_backoff: Duration::from_millis(config.retry_backoff.max(1)),
+            specs: vec![],
+            last_flush: Instant::now(),
+            period_start: now_nanos(),
+        })
+    }
+
+    fn push(&mut self, write_specs: &[WriteSpec]) {
+        self.specs.extend_from_slice(write_specs);
+    }
+
+    fn is_full(&self) -> bool {
+        self.specs.len() >= self.batch_size
+    }
+
+    fn is_due(&self) -> bool {
+        !self.specs.is_empty() && self.last_flush.elapsed() >= self.flush_interval
+    }
+
+    /// Exports the buffered points, returns the number of points exported.
+    async fn flush(&mut self) -> Result<usize, FlushError> {
+        self.last_flush = Instant::now();
+        if self.specs.is_empty() {
+            return Ok(0);
+        }
+        let specs = std::mem::take(&mut self.specs);
+        let period_end = now_nanos();
+        let request = build_request(
+            &specs,
+            &self.service_name,
+            &self.bounds,
+            self.period_start,
+            period_end,
+        );
+        self.period_start = period_end;
+        let body = request.encode_to_vec();
+
+        let mut backoff = self.retry_backoff;
+        let mut attempt = 0;
+        loop {
+            match self.send(body.clone()).await {
+                Ok(_) => {
+                    log::debug!("{} point(s) exported to otlp", specs.len());
+                    return Ok(specs.len());
+                }
+                Err(WriteError::Retry(e)) if attempt < self.max_retries => {
+                    attempt += 1;
+                    log::warn!(
+                        "otlp export error, retry {}/{} in {:?}:{:?}",
+                        attempt,
+                        self.max_retries,
+                        backoff,
+                        e
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+                Err(WriteError::Retry(e)) | Err(WriteError::Reject(e)) => {
+                    return Err(FlushError::new(specs.len(), e.context("otlp export error")));
+                }
+            }
+        }
+    }
+
+    async fn send(&self, body: Vec<u8>) -> Result<(), WriteError> {
+        let mut request = self
+            .client
+            .post(&self.endpoint)
+            .header(CONTENT_TYPE, "application/x-protobuf")
+            .header(USER_AGENT, concat!("tsample/", env!("CARGO_PKG_VERSION")))
+            .body(body);
+        for (name, value) in self.headers.iter() {
+            request = request.header(name, value);
+        }
+        let res = match request.send().await {
+            Ok(res) => res,
+            Err(e) => return Err(WriteError::Retry(e.into())),
+        };
+        let status = res.status();
+        if !status.is_success() {
+            let text = res.text().await.unwrap_or_default();
+            let e = anyhow::anyhow!("otlp export failed, status:{}, response:{}", status, text);
+            // the retryable status codes of the OTLP/HTTP specification.
+            return match status.as_u16() {
+                429 | 502 | 503 | 504 => Err(WriteError::Retry(e)),
+                _ => Err(WriteError::Reject(e)),
+            };
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use influxdb::{Timestamp, Type};
+
+    #[test]
+    fn test_build_request() {
+        let spec = WriteSpec::new(Timestamp::Milliseconds(1000), "ConnectionServer")
+            .add_tag("Platform", Type::Text("p1".to_string()))
+            .add_tag("cxserver", Type::Text("cx1".to_string()))
+            .add_field("queue size", Type::SignedInteger(3))
+            .add_field("ResponseTime", Type::SignedInteger(150000000));
+        let spec2 = WriteSpec::new(Timestamp::Milliseconds(2000), "ConnectionServer")
+            .add_tag("Platform", Type::Text("p1".to_string()))
+            .add_tag("cxserver", Type::Text("cx1".to_string()))
+            .add_field("ResponseTime", Type::SignedInteger(500000000));
+
+        let request = build_request(&[spec, spec2], "tsample", &[100.0, 400.0], 1, 2);
+        assert_eq!(request.resource_metrics.len(), 1);
+        let resource = request.resource_metrics[0].resource.as_ref().unwrap();
+        assert_eq!(resource.attributes[1].key, "Platform");
+
+        let metrics = &request.resource_metrics[0].scope_metrics[0].metrics;
+        assert_eq!(metrics.len(), 2);
+        assert_eq!(metrics[0].name, "ConnectionServer.ResponseTime");
+        let point = &metrics[0].histogram.as_ref().unwrap().data_points[0];
+        assert_eq!(point.count, 2);
+        assert_eq!(point.bucket_counts, vec![0, 1, 1]);
+        assert_eq!(point.sum, Some(650.0));
+        assert_eq!(
+            point.attributes,
+            key_values(&[("cxserver".to_string(), "cx1".to_string())])
+        );
+
+        assert_eq!(metrics[1].name, "ConnectionServer.queue_size");
+        let point = &metrics[1].gauge.as_ref().unwrap().data_points[0];
+        assert_eq!(point.as_double, Some(3.0));
+        assert_eq!(point.time_unix_nano, 1000000000);
+
+        let decoded =
+            ExportMetricsServiceRequest::decode(request.encode_to_vec().as_slice()).unwrap();
+        assert_eq!(decoded, request);
+    }
+}
@@ -1,130 +1,548 @@
 use std::{
-    collections::HashMap,
-    net::SocketAddr,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fs,
+    net::{SocketAddr, TcpListener, ToSocketAddrs},
     sync::{Arc, RwLock},
+    time::{Duration, Instant},
 };
 
+use crate::reload::ConfigReceiver;
+use crate::scheduler::longest_interval;
+use crate::selfmetrics::{INTERNAL_MEASUREMENT, PROMETHEUS_CONFLICTS};
+use crate::sink::Sink;
 use crate::spec::WriteSpec;
 use crate::testconfig::ExportToPrometheus;
+use anyhow::Context;
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD, Engine};
 use influxdb::Type;
 use lazy_static::lazy_static;
-use prometheus::{GaugeVec, HistogramOpts, HistogramVec, Opts, Registry};
-use tokio::sync::mpsc::Receiver;
-use warp::{Filter, Rejection, Reply};
+use prometheus::{
+    proto::{Gauge, LabelPair, Metric, MetricFamily, MetricType},
+    HistogramOpts, HistogramVec, Registry,
+};
+use tokio::task::JoinHandle;
+use warp::{
+    http::{
+        header::{CONTENT_TYPE, WWW_AUTHENTICATE},
+        Response, StatusCode,
+    },
+    path::FullPath,
+    Filter, Rejection,
+};
+
+// the stale series are looked for every second, the intervals can change on reload.
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(1);
 
 lazy_static! {
     pub static ref REGISTRY: Registry = Registry::new();
+    pub static ref GAUGE_FAMILIES: RwLock<GaugeFamilies> = RwLock::new(GaugeFamilies::default());
+}
+
+pub struct PrometheusSink {
+    response_time: HistogramVec,
+    // the HTTP server, stopped when the sink is closed.
+    server: JoinHandle<()>,
+    // a series is removed once it missed this many scrape cycles, 0 keeps it forever.
+    stale_series_cycles: u32,
+    stale_after: Duration,
+    // the intervals of the sources can change on reload, without a new sink.
+    config: ConfigReceiver,
+    // the last update of each response time label.
+    services: HashMap<String, Instant>,
+}
+
+impl PrometheusSink {
+    /// The series are removed after `stale_series_cycles` of the longest interval of the
+    /// sources, so a source queried less often than `scrap_interval` keeps its series.
+    pub async fn new(etp: ExportToPrometheus, mut config: ConfigReceiver) -> anyhow::Result<Self> {
+        log::info!("Prometheus metric service initialization...");
+        // HistogramVec, only response time
+        let bucket_bin = etp.response_time_bucket_bin.clone();
+        let opts =
+            HistogramOpts::new("ResponseTime", "Response time in milliseconds").buckets(bucket_bin);
+        let response_time = HistogramVec::new(opts, &["Service"])?;
+
+        // let counter_list = etp.counter_list.clone();
+
+        log::info!("Lunching Prometheus metric service...");
+        let server = launch_prometheus_service(&etp).await?;
+
+        if let Err(e) = REGISTRY.register(Box::new(response_time.clone())) {
+            server.abort();
+            return Err(e.into());
+        }
+        log::debug!("Response time metric registered.");
+
+        let stale_series_cycles = etp.stale_series_cycles as u32;
+        let stale_after = longest_interval(&config.borrow_and_update()) * stale_series_cycles;
+        Ok(PrometheusSink {
+            response_time,
+            server,
+            stale_series_cycles,
+            stale_after,
+            config,
+            services: HashMap::new(),
+        })
+    }
 }
 
-pub async fn prometheus_thread(
-    etp: ExportToPrometheus,
-    mut receiver: Receiver<Vec<WriteSpec>>,
-) -> anyhow::Result<()> {
-    log::info!("Prometheus metric service initialization...");
-    // HistogramVec, only response time
-    let bucket_bin = etp.response_time_bucket_bin.clone();
-    let opts =
-        HistogramOpts::new("ResponseTime", "Response time in milliseconds").buckets(bucket_bin);
-    let response_time = HistogramVec::new(opts, &["Service"])?;
-
-    REGISTRY.register(Box::new(response_time.clone()))?;
-    log::debug!("Response time metric registered.");
-
-    let gauge_map: Arc<RwLock<HashMap<String, GaugeVec>>> = Arc::new(RwLock::new(HashMap::new()));
-
-    // let counter_list = etp.counter_list.clone();
-
-    log::info!("Lunching Prometheus metric service...");
-    launch_prometheus_service(&etp).await?;
-
-    loop {
-        match receiver.recv().await {
-            None => break,
-            Some(write_specs) => {
-                for write_spec in write_specs {
-                    for field in write_spec.fields {
-                        let name = format!("{}_{}", write_spec.measurement, field.0);
-                        let value = match field.1 {
-                            Type::Boolean(_) => {
-                                continue;
-                            }
-                            Type::Float(value) => value,
-                            Type::SignedInteger(value) => value as f64,
-                            Type::UnsignedInteger(value) => value as f64,
-                            Type::Text(_) => {
-                                continue;
-                            }
-                        };
-
-                        if field.0 == "ResponseTime" {
-                            response_time
-                                .with_label_values(&[&write_spec.measurement])
-                                .observe(value / 1000000.0); // convert to milliseconds from nanoseconds
-                        } else {
-                            let map = gauge_map.read().expect("Read Lock poisoned.");
-                            let mut label_values: Vec<&str> = vec![];
-                            for tag in write_spec.tags.iter() {
-                                match &tag.1 {
-                                    Type::Text(value) => label_values.push(value),
-                                    _ => {
-                                        continue;
-                                    }
-                                }
-                            }
-                            if let Some(counter) = map.get(&name) {
-                                counter.with_label_values(&label_values).set(value);
-                            } else {
-                                drop(map);
-                                let mut map = gauge_map.write().expect("Write Lock poisoned.");
-                                let mut label_names: Vec<&str> = vec![];
-                                for tag in write_spec.tags.iter() {
-                                    label_names.push(&tag.0);
-                                }
-
-                                let counter = GaugeVec::new(
-                                    Opts::new(&name, &format!("{} Gauge", name)),
-                                    &label_names,
-                                )?;
-                                REGISTRY.register(Box::new(counter.clone()))?;
-                                counter.with_label_values(&label_values).set(value);
-                                map.entry(name).or_insert_with(|| counter);
-                            };
-                        }
-                    }
+#[async_trait]
+impl Sink for PrometheusSink {
+    async fn write(&mut self, write_specs: &[WriteSpec]) -> anyhow::Result<usize> {
+        let mut families = GAUGE_FAMILIES.write().expect("Write Lock poisoned.");
+        for write_spec in write_specs.iter() {
+            // already exposed from the registry.
+            if write_spec.measurement == INTERNAL_MEASUREMENT {
+                continue;
+            }
+            let labels = match spec_labels(write_spec) {
+                Ok(labels) => labels,
+                Err(conflict) => {
+                    families.report_conflict(&write_spec.measurement, conflict);
+                    continue;
+                }
+            };
+            for (field, value) in write_spec.fields.iter() {
+                let value = match gauge_value(value) {
+                    Some(value) => value,
+                    None => continue,
+                };
+
+                if field == "ResponseTime" {
+                    self.response_time
+                        .with_label_values(&[&write_spec.measurement])
+                        .observe(value / 1000000.0); // convert to milliseconds from nanoseconds
+                    self.services
+                        .insert(write_spec.measurement.clone(), Instant::now());
+                } else {
+                    families.set(&write_spec.measurement, field, &labels, value);
                 }
             }
         }
+        Ok(write_specs.len())
+    }
+
+    async fn tick(&mut self) -> anyhow::Result<usize> {
+        if self.config.has_changed().unwrap_or(false) {
+            self.stale_after =
+                longest_interval(&self.config.borrow_and_update()) * self.stale_series_cycles;
+        }
+        let stale_after = self.stale_after;
+        let removed = GAUGE_FAMILIES
+            .write()
+            .expect("Write Lock poisoned.")
+            .expire(stale_after);
+        let response_time = &self.response_time;
+        self.services.retain(|service, last_update| {
+            if last_update.elapsed() <= stale_after {
+                return true;
+            }
+            let _ = response_time.remove_label_values(&[service]);
+            log::debug!("stale response time removed, service:{}", service);
+            false
+        });
+        if removed > 0 {
+            log::info!("{} stale Prometheus series removed", removed);
+        }
+        Ok(0)
+    }
+
+    fn tick_interval(&self) -> Option<Duration> {
+        if self.stale_series_cycles == 0 {
+            None
+        } else {
+            Some(EXPIRE_INTERVAL)
+        }
+    }
+
+    /// Stops the HTTP server, so a new configuration can be served. The gauges are kept.
+    async fn close(&mut self) -> anyhow::Result<usize> {
+        self.server.abort();
+        let _ = (&mut self.server).await;
+        REGISTRY.unregister(Box::new(self.response_time.clone()))?;
+        log::info!("Prometheus metric service stopped.");
+        Ok(0)
     }
-    todo!()
 }
 
-pub async fn launch_prometheus_service(etp: &ExportToPrometheus) -> anyhow::Result<()> {
-    let metrics_route = warp::path!("metrics").and_then(metrics_handler);
-    let addr = format!("{}:{}", "0.0.0.0", etp.port);
-    log::info!("Prometheus metric service will be launched on {}", addr);
-    let metrics_addr: SocketAddr = match addr.parse() {
-        Ok(value) => value,
-        Err(e) => {
-            log::error!("Failed to parse:{} as addr,error:{:?}", addr, e);
-            "0.0.0.0:19090".parse().unwrap()
+/// Label set of one series, sorted by the (normalized) label name.
+pub type Labels = BTreeMap<String, String>;
+
+/// Gauges built from the `WriteSpec` fields, one family per measurement and field.
+///
+/// The label names of a family are the union of the tags seen for it: a series without
+/// one of the labels exports it with an empty value, which Prometheus treats as absent.
+/// Specs that can't be mapped are reported once and skipped, instead of failing the exporter.
+#[derive(Default)]
+pub struct GaugeFamilies {
+    families: HashMap<String, GaugeFamily>,
+    reported: HashSet<String>,
+}
+
+struct GaugeFamily {
+    // the measurement and the field this family is built from.
+    source: (String, String),
+    label_names: BTreeSet<String>,
+    // the value and the time of the last update.
+    series: HashMap<Labels, (f64, Instant)>,
+}
+
+impl GaugeFamilies {
+    pub fn set(&mut self, measurement: &str, field: &str, labels: &Labels, value: f64) {
+        let name = metric_name(measurement, field);
+        let family = match self.families.get_mut(&name) {
+            Some(family) => family,
+            None => {
+                if is_registered(&name) {
+                    self.report_conflict(
+                        &name,
+                        format!("{} is already used by another metric", name),
+                    );
+                    return;
+                }
+                self.families.entry(name.clone()).or_insert(GaugeFamily {
+                    source: (measurement.to_string(), field.to_string()),
+                    label_names: BTreeSet::new(),
+                    series: HashMap::new(),
+                })
+            }
+        };
+        if family.source.0 != measurement || family.source.1 != field {
+            let conflict = format!(
+                "{}/{} and {}/{} are both exported as {}",
+                family.source.0, family.source.1, measurement, field, name
+            );
+            self.report_conflict(&name, conflict);
+            return;
+        }
+        for label_name in labels.keys() {
+            if !family.label_names.contains(label_name) {
+                family.label_names.insert(label_name.clone());
+            }
+        }
+        family
+            .series
+            .insert(labels.clone(), (value, Instant::now()));
+    }
+
+    /// Removes the series not updated for `max_age` and returns how many were removed.
+    /// A family without series is removed too, so its label names start over.
+    pub fn expire(&mut self, max_age: Duration) -> usize {
+        let mut removed = 0;
+        self.families.retain(|name, family| {
+            let count = family.series.len();
+            family
+                .series
+                .retain(|_, (_, last_update)| last_update.elapsed() <= max_age);
+            if family.series.len() == count {
+                return true;
+            }
+            removed += count - family.series.len();
+            log::debug!(
+                "{} stale series removed from {}",
+                count - family.series.len(),
+                name
+            );
+            family.label_names = family
+                .series
+                .keys()
+                .flat_map(|labels| labels.keys().cloned())
+                .collect();
+            !family.series.is_empty()
+        });
+        removed
+    }
+
+    /// Counts the conflict, it's logged the first time it happens for this key.
+    pub fn report_conflict(&mut self, key: &str, conflict: String) {
+        PROMETHEUS_CONFLICTS.inc();
+        if self.reported.insert(key.to_string()) {
+            log::warn!("Prometheus metric skipped, {}", conflict);
+        }
+    }
+
+    pub fn gather(&self) -> Vec<MetricFamily> {
+        let mut result = vec![];
+        for (name, family) in self.families.iter() {
+            if family.series.is_empty() {
+                continue;
+            }
+            let mut metric_family = MetricFamily::default();
+            metric_family.set_name(name.clone());
+            metric_family.set_help(format!("{} Gauge", name));
+            metric_family.set_field_type(MetricType::GAUGE);
+            for (labels, (value, _)) in family.series.iter() {
+                let mut metric = Metric::default();
+                for label_name in family.label_names.iter() {
+                    let mut label = LabelPair::default();
+                    label.set_name(label_name.clone());
+                    label.set_value(labels.get(label_name).cloned().unwrap_or_default());
+                    metric.mut_label().push(label);
+                }
+                let mut gauge = Gauge::default();
+                gauge.set_value(*value);
+                metric.set_gauge(gauge);
+                metric_family.mut_metric().push(metric);
+            }
+            result.push(metric_family);
+        }
+        result.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+        result
+    }
+}
+
+fn is_registered(name: &str) -> bool {
+    REGISTRY
+        .gather()
+        .iter()
+        .any(|family| family.get_name() == name)
+}
+
+/// Prometheus metric name of one field: `{measurement}_{field}`, with the invalid characters replaced.
+pub fn metric_name(measurement: &str, field: &str) -> String {
+    let mut name = String::with_capacity(measurement.len() + field.len() + 1);
+    for (i, c) in measurement
+        .chars()
+        .chain(Some('_'))
+        .chain(field.chars())
+        .enumerate()
+    {
+        let valid =
+            c.is_ascii_alphabetic() || c == '_' || c == ':' || (i > 0 && c.is_ascii_digit());
+        name.push(if valid { c } else { '_' });
+    }
+    name
+}
+
+/// Prometheus label name of one tag, with the invalid characters replaced.
+/// Names starting with "__" are reserved by Prometheus, they get a "tag" prefix.
+pub fn label_name(tag: &str) -> String {
+    let mut name = String::with_capacity(tag.len() + 3);
+    for (i, c) in tag.chars().enumerate() {
+        let valid = c.is_ascii_alphabetic() || c == '_' || (i > 0 && c.is_ascii_digit());
+        name.push(if valid { c } else { '_' });
+    }
+    if name.is_empty() || name.starts_with("__") {
+        name.insert_str(0, "tag");
+    }
+    name
+}
+
+/// Maps the tags of the spec to Prometheus labels, empty values are left out.
+/// Fails if two tags end up with the same label name.
+pub fn spec_labels(spec: &WriteSpec) -> Result<Labels, String> {
+    let mut labels = Labels::new();
+    for (tag, value) in spec.tags.iter() {
+        let value = value.to_string();
+        if value.is_empty() {
+            continue;
+        }
+        let name = label_name(tag);
+        if labels.insert(name.clone(), value).is_some() {
+            return Err(format!(
+                "{} has more than one tag exported as label {}",
+                spec.measurement, name
+            ));
+        }
+    }
+    Ok(labels)
+}
+
+/// Only numbers can be exported as gauges.
+pub fn gauge_value(value: &Type) -> Option<f64> {
+    match value {
+        Type::Float(value) => Some(*value),
+        Type::SignedInteger(value) => Some(*value as f64),
+        Type::UnsignedInteger(value) => Some(*value as f64),
+        Type::Boolean(_) | Type::Text(_) => None,
+    }
+}
+
+pub async fn launch_prometheus_service(etp: &ExportToPrometheus) -> anyhow::Result<JoinHandle<()>> {
+    let endpoint = Arc::new(etp.endpoint.trim_matches('/').to_string());
+    let auth = Arc::new(MetricsAuth::new(etp)?);
+    let metrics_route = warp::get()
+        .and(warp::path::full())
+        .and(warp::header::optional::<String>("authorization"))
+        .and_then(move |path: FullPath, authorization: Option<String>| {
+            let endpoint = endpoint.clone();
+            let auth = auth.clone();
+            async move { metrics_handler(path, authorization, &endpoint, &auth).await }
+        });
+
+    let metrics_addr = match (etp.bind_address.as_str(), etp.port)
+        .to_socket_addrs()?
+        .next()
+    {
+        Some(addr) => addr,
+        None => {
+            return Err(anyhow::anyhow!(
+                "Failed to resolve:{} as listen address",
+                etp.bind_address
+            ))
         }
     };
+    log::info!(
+        "Prometheus metric service will be launched on {}, endpoint:/{}",
+        metrics_addr,
+        etp.endpoint.trim_matches('/')
+    );
+
+    match (etp.tls_cert.as_ref(), etp.tls_key.as_ref()) {
+        (Some(cert), Some(key)) => {
+            let (cert, key) = read_tls_files(cert, key)?;
+            // warp panics when the TLS server can't bind, the address is checked first.
+            drop(bind_error(TcpListener::bind(metrics_addr), metrics_addr)?);
+            let (_, server) = warp::serve(metrics_route)
+                .tls()
+                .cert(cert)
+                .key(key)
+                .bind_ephemeral(metrics_addr);
+            let server = tokio::spawn(server);
+            log::info!("Prometheus metric HTTPS service launched.");
+            Ok(server)
+        }
+        (None, None) => {
+            let (_, server) = bind_error(
+                warp::serve(metrics_route).try_bind_ephemeral(metrics_addr),
+                metrics_addr,
+            )?;
+            let server = tokio::spawn(server);
+            log::info!("Prometheus metric HTTP service launched.");
+            Ok(server)
+        }
+        _ => Err(anyhow::anyhow!(
+            "both tls_cert and tls_key are needed to serve Prometheus metrics over HTTPS"
+        )),
+    }
+}
+
+fn bind_error<T, E: std::fmt::Display>(
+    result: Result<T, E>,
+    addr: SocketAddr,
+) -> anyhow::Result<T> {
+    result.map_err(|e| anyhow::anyhow!("failed to listen on {}:{}", addr, e))
+}
+
+/// Reads the certificate chain and the private key of the HTTPS endpoint, and checks them
+/// the way the server will, so an invalid file is reported instead of stopping the server.
+fn read_tls_files(cert: &str, key: &str) -> anyhow::Result<(Vec<u8>, Vec<u8>)> {
+    let cert_pem = fs::read(cert).with_context(|| format!("failed to read tls_cert:{}", cert))?;
+    let key_pem = fs::read(key).with_context(|| format!("failed to read tls_key:{}", key))?;
+
+    let certs = rustls_pemfile::certs(&mut cert_pem.as_slice())
+        .with_context(|| format!("failed to parse tls_cert:{}", cert))?;
+    if certs.is_empty() {
+        return Err(anyhow::anyhow!("no certificate found in tls_cert:{}", cert));
+    }
+    let mut keys = rustls_pemfile::pkcs8_private_keys(&mut key_pem.as_slice())
+        .with_context(|| format!("failed to parse tls_key:{}", key))?;
+    if keys.is_empty() {
+        keys = rustls_pemfile::rsa_private_keys(&mut key_pem.as_slice())
+            .with_context(|| format!("failed to parse tls_key:{}", key))?;
+    }
+    let key_der = match keys.into_iter().next() {
+        Some(key_der) => key_der,
+        None => return Err(anyhow::anyhow!("no private key found in tls_key:{}", key)),
+    };
+    rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(
+            certs.into_iter().map(rustls::Certificate).collect(),
+            rustls::PrivateKey(key_der),
+        )
+        .with_context(|| format!("invalid tls_cert:{} or tls_key:{}", cert, key))?;
+    Ok((cert_pem, key_pem))
+}
+
+/// Credentials accepted by the metrics endpoint, no authentication if both are empty.
+struct MetricsAuth {
+    // the expected value of the Authorization header, like "Basic dXNlcjpwYXNz".
+    basic: Option<String>,
+    bearer: Option<String>,
+}
+
+impl MetricsAuth {
+    fn new(etp: &ExportToPrometheus) -> anyhow::Result<Self> {
+        let basic = match (etp.username.as_ref(), etp.password.as_ref()) {
+            (Some(username), Some(password)) => Some(format!(
+                "Basic {}",
+                STANDARD.encode(format!("{}:{}", username, password))
+            )),
+            (None, None) => None,
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "both username and password are needed for the Prometheus basic authentication"
+                ))
+            }
+        };
+        let bearer = etp
+            .bearer_token
+            .as_ref()
+            .map(|token| format!("Bearer {}", token));
+        Ok(MetricsAuth { basic, bearer })
+    }
+
+    fn is_required(&self) -> bool {
+        self.basic.is_some() || self.bearer.is_some()
+    }
+
+    fn is_authorized(&self, authorization: Option<&str>) -> bool {
+        if !self.is_required() {
+            return true;
+        }
+        let authorization = match authorization {
+            Some(authorization) => authorization.trim(),
+            None => return false,
+        };
+        [self.basic.as_ref(), self.bearer.as_ref()]
+            .iter()
+            .flatten()
+            .any(|expected| constant_time_eq(expected.as_bytes(), authorization.as_bytes()))
+    }
+}
 
-    tokio::spawn(warp::serve(metrics_route).run(metrics_addr));
-    log::info!("Prometheus metric HTTP service launched.");
-    Ok(())
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
 }
-pub async fn metrics_handler() -> Result<impl Reply, Rejection> {
-    let res = retrieve_metrics().await;
-    Ok(res)
+
+async fn metrics_handler(
+    path: FullPath,
+    authorization: Option<String>,
+    endpoint: &str,
+    auth: &MetricsAuth,
+) -> Result<Response<String>, Rejection> {
+    if path.as_str().trim_matches('/') != endpoint {
+        return Err(warp::reject::not_found());
+    }
+    let response = if auth.is_authorized(authorization.as_deref()) {
+        Response::builder()
+            .header(CONTENT_TYPE, prometheus::TEXT_FORMAT)
+            .body(retrieve_metrics().await)
+    } else {
+        let mut builder = Response::builder().status(StatusCode::UNAUTHORIZED);
+        if auth.basic.is_some() {
+            builder = builder.header(WWW_AUTHENTICATE, "Basic realm=\"tsample\"");
+        }
+        builder.body(String::default())
+    };
+    // the builder only fails with invalid headers, which are all constants here.
+    Ok(response.unwrap_or_default())
 }
 
 pub async fn retrieve_metrics() -> String {
     use prometheus::Encoder;
     let encoder = prometheus::TextEncoder::new();
 
+    let mut metric_families = REGISTRY.gather();
+    metric_families.extend(GAUGE_FAMILIES.read().expect("Read Lock poisoned.").gather());
     let mut buffer = Vec::new();
-    if let Err(e) = encoder.encode(&REGISTRY.gather(), &mut buffer) {
+    if let Err(e) = encoder.encode(&metric_families, &mut buffer) {
         eprintln!("could not encode custom metrics: {}", e);
     };
     let res = match String::from_utf8(buffer.clone()) {
@@ -155,3 +573,133 @@ pub async fn retrieve_metrics() -> String {
 
     res
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> ExportToPrometheus {
+        serde_yaml::from_str("enabled: true").unwrap()
+    }
+
+    #[test]
+    fn test_metrics_auth() {
+        let auth = MetricsAuth::new(&config()).unwrap();
+        assert!(auth.is_authorized(None));
+
+        let mut etp = config();
+        etp.username = Some("user".to_string());
+        etp.password = Some("pass".to_string());
+        etp.bearer_token = Some("token".to_string());
+        let auth = MetricsAuth::new(&etp).unwrap();
+        assert!(!auth.is_authorized(None));
+        assert!(auth.is_authorized(Some("Basic dXNlcjpwYXNz")));
+        assert!(auth.is_authorized(Some("Bearer token")));
+        assert!(!auth.is_authorized(Some("Bearer other")));
+
+        etp.password = None;
+        assert!(MetricsAuth::new(&etp).is_err());
+    }
+
+    #[test]
+    fn test_metric_and_label_names() {
+        assert_eq!(
+            metric_name("PersistentProperty.Metrics", "queue-size"),
+            "PersistentProperty_Metrics_queue_size"
+        );
+        assert_eq!(metric_name("1st", "v"), "_st_v");
+        assert_eq!(label_name("sub_name"), "sub_name");
+        assert_eq!(label_name("cx server"), "cx_server");
+        assert_eq!(label_name("__name__"), "tag__name__");
+    }
+
+    #[test]
+    fn test_gauge_families_union_labels() {
+        let mut families = GaugeFamilies::default();
+        let spec = WriteSpec::new(influxdb::Timestamp::Milliseconds(0), "ConnectionServer")
+            .add_tag("Platform", Type::Text("p1".to_string()))
+            .add_field("v", Type::Float(1.0));
+        families.set("ConnectionServer", "v", &spec_labels(&spec).unwrap(), 1.0);
+        let spec = spec.add_tag("cxserver", Type::Text("cx1".to_string()));
+        families.set("ConnectionServer", "v", &spec_labels(&spec).unwrap(), 2.0);
+
+        let gathered = families.gather();
+        assert_eq!(gathered.len(), 1);
+        let metrics = gathered[0].get_metric();
+        assert_eq!(metrics.len(), 2);
+        for metric in metrics {
+            let names: Vec<&str> = metric.get_label().iter().map(|l| l.get_name()).collect();
+            assert_eq!(names, vec!["Platform", "cxserver"]);
+        }
+        let mut buffer = vec![];
+        prometheus::Encoder::encode(&prometheus::TextEncoder::new(), &gathered, &mut buffer)
+            .unwrap();
+        let text = String::from_utf8(buffer).unwrap();
+        assert!(text.contains(r#"ConnectionServer_v{Platform="p1",cxserver=""} 1"#));
+        assert!(text.contains(r#"ConnectionServer_v{Platform="p1",cxserver="cx1"} 2"#));
+    }
+
+    #[test]
+    fn test_gauge_families_conflicts() {
+        let mut families = GaugeFamilies::default();
+        let conflicts = PROMETHEUS_CONFLICTS.get();
+        families.set("a.b", "c", &Labels::new(), 1.0);
+        families.set("a_b", "c", &Labels::new(), 2.0);
+        assert_eq!(PROMETHEUS_CONFLICTS.get(), conflicts + 1);
+        assert_eq!(
+            families.gather()[0].get_metric()[0].get_gauge().get_value(),
+            1.0
+        );
+
+        let spec = WriteSpec::new(influxdb::Timestamp::Milliseconds(0), "m")
+            .add_tag("a b", Type::Text("1".to_string()))
+            .add_tag("a_b", Type::Text("2".to_string()));
+        assert!(spec_labels(&spec).is_err());
+    }
+
+    #[test]
+    fn test_gauge_families_expire() {
+        let mut families = GaugeFamilies::default();
+        let labels = Labels::from([("cxserver".to_string(), "cx1".to_string())]);
+        families.set("ConnectionServer", "v", &labels, 1.0);
+        assert_eq!(families.expire(Duration::from_secs(60)), 0);
+        std::thread::sleep(Duration::from_millis(20));
+        families.set("ConnectionServer", "v", &Labels::new(), 2.0);
+        assert_eq!(families.expire(Duration::from_millis(10)), 1);
+
+        let gathered = families.gather();
+        assert_eq!(gathered[0].get_metric().len(), 1);
+        assert!(gathered[0].get_metric()[0].get_label().is_empty());
+
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(families.expire(Duration::from_millis(10)), 1);
+        assert!(families.gather().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_launch_errors() {
+        // the port is already used.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut etp = config();
+        etp.bind_address = "127.0.0.1".to_string();
+        etp.port = listener.local_addr().unwrap().port();
+        let error = launch_prometheus_service(&etp).await.unwrap_err();
+        assert!(error.to_string().starts_with("failed to listen on"));
+
+        let directory =
+            std::env::temp_dir().join(format!("tsample-prometheus-tls-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let cert = directory.join("cert.pem");
+        fs::write(&cert, "not a certificate").unwrap();
+        etp.port = 0;
+        etp.tls_cert = Some(cert.display().to_string());
+        etp.tls_key = Some(directory.join("missing.pem").display().to_string());
+        let error = launch_prometheus_service(&etp).await.unwrap_err();
+        assert!(error.to_string().starts_with("failed to read tls_key"));
+
+        etp.tls_key = etp.tls_cert.clone();
+        let error = launch_prometheus_service(&etp).await.unwrap_err();
+        assert!(error.to_string().starts_with("no certificate found"));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
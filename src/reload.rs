@@ -0,0 +1,167 @@
+use std::{fs, future::pending, path::PathBuf, sync::Arc, time::Duration, time::SystemTime};
+
+use crate::include;
+use crate::selfmetrics::CONFIG_RELOADS;
+use crate::testconfig::TestConfig;
+use tokio::sync::watch;
+
+/// The running configuration, replaced as a whole when the file changes.
+pub type ConfigReceiver = watch::Receiver<Arc<TestConfig>>;
+
+#[cfg(unix)]
+type Hangup = Option<tokio::signal::unix::Signal>;
+#[cfg(not(unix))]
+type Hangup = Option<()>;
+
+/// Checks the configuration file and the files it includes every `config_reload_interval`
+/// seconds and on SIGHUP, and sends the new configuration when it changed. A configuration that fails to load or
+/// to validate is rejected, the current one keeps running.
+pub async fn watch_config(file_name: String, sender: watch::Sender<Arc<TestConfig>>) {
+    let mut modified = modified_times(&file_name);
+    let mut hangup = hangup_signal();
+    loop {
+        let interval = sender.borrow().config_reload_interval;
+        let poll = async {
+            if interval == 0 {
+                pending::<()>().await;
+            }
+            tokio::time::sleep(Duration::from_secs(interval)).await;
+        };
+        let signaled = tokio::select! {
+            _ = poll => false,
+            _ = wait_hangup(&mut hangup) => true,
+        };
+        let current = modified_times(&file_name);
+        if !signaled && current == modified {
+            continue;
+        }
+        modified = current;
+        if signaled {
+            log::info!("Received SIGHUP, reloading configuration {}", file_name);
+        }
+        reload(&file_name, &sender);
+    }
+}
+
+fn reload(file_name: &str, sender: &watch::Sender<Arc<TestConfig>>) {
+    let tc = match TestConfig::load_valid(file_name) {
+        Ok(tc) => tc,
+        Err(e) => {
+            CONFIG_RELOADS.with_label_values(&["failure"]).inc();
+            log::error!(
+                "configuration {} rejected, the previous one keeps running:{:#}",
+                file_name,
+                e
+            );
+            return;
+        }
+    };
+    // compared as YAML, like the flattened configuration.
+    let unchanged =
+        serde_yaml::to_string(&tc).ok() == serde_yaml::to_string(&**sender.borrow()).ok();
+    if unchanged {
+        log::info!("configuration {} reloaded, nothing changed", file_name);
+        return;
+    }
+    CONFIG_RELOADS.with_label_values(&["success"]).inc();
+    log::info!("configuration {} reloaded, applying the changes", file_name);
+    sender.send_replace(Arc::new(tc));
+}
+
+/// Sleeps for the duration, or until the configuration changes.
+pub async fn sleep_or_reload(config: &mut ConfigReceiver, duration: Duration) {
+    tokio::select! {
+        _ = tokio::time::sleep(duration) => {}
+        Ok(_) = config.changed() => {}
+    }
+}
+
+// a file added to or removed from conf.d changes the list.
+fn modified_times(file_name: &str) -> Vec<(PathBuf, Option<SystemTime>)> {
+    include::watched_files(file_name)
+        .into_iter()
+        .map(|path| {
+            let modified = fs::metadata(&path).and_then(|m| m.modified()).ok();
+            (path, modified)
+        })
+        .collect()
+}
+
+#[cfg(unix)]
+fn hangup_signal() -> Hangup {
+    use tokio::signal::unix::{signal, SignalKind};
+    match signal(SignalKind::hangup()) {
+        Ok(signal) => Some(signal),
+        Err(e) => {
+            log::warn!("failed to listen to SIGHUP:{:?}", e);
+            None
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn hangup_signal() -> Hangup {
+    None
+}
+
+#[cfg(unix)]
+async fn wait_hangup(hangup: &mut Hangup) {
+    match hangup {
+        Some(signal) => {
+            if signal.recv().await.is_none() {
+                *hangup = None;
+            }
+        }
+        None => pending().await,
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_hangup(_: &mut Hangup) {
+    pending().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reload_keeps_the_valid_config() {
+        let file = std::env::temp_dir().join(format!("tsample-reload-{}.yaml", std::process::id()));
+        let file_name = file.to_str().unwrap();
+        let config = |subsystems: &str| {
+            format!(
+                "thingworx_servers:\n  - {{name: p1, host: localhost, port: 8080, app_key: k, subsystems: [{}]}}\n\
+                 export_to_influxdb: {{server_name: localhost, port: 8086, enabled: false}}\n",
+                subsystems
+            )
+        };
+        fs::write(&file, config("{name: EventProcessingSubsystem}")).unwrap();
+        let (sender, mut receiver) =
+            watch::channel(Arc::new(TestConfig::load_from_file(file_name).unwrap()));
+
+        // unchanged
+        reload(file_name, &sender);
+        assert!(!receiver.has_changed().unwrap());
+
+        // invalid, the server port is 0
+        fs::write(
+            &file,
+            config("{name: EventProcessingSubsystem}").replace("8080", "0"),
+        )
+        .unwrap();
+        reload(file_name, &sender);
+        assert!(!receiver.has_changed().unwrap());
+
+        fs::write(
+            &file,
+            config("{name: EventProcessingSubsystem}, {name: ValueStreamProcessingSubsystem}"),
+        )
+        .unwrap();
+        reload(file_name, &sender);
+        assert!(receiver.has_changed().unwrap());
+        let tc = receiver.borrow_and_update().clone();
+        assert_eq!(tc.thingworx_servers[0].subsystems.len(), 2);
+        fs::remove_file(&file).unwrap();
+    }
+}
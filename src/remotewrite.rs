@@ -0,0 +1,326 @@
+use std::{
+    collections::HashSet,
+    time::{Duration, Instant},
+};
+
+use crate::influx::WriteError;
+use crate::lineprotocol::timestamp_in;
+use crate::prometheus::{gauge_value, metric_name, spec_labels};
+use crate::sink::{FlushError, Sink};
+use crate::spec::WriteSpec;
+use crate::testconfig::ExportToRemoteWrite;
+use async_trait::async_trait;
+use prost::Message;
+use reqwest::header::{AUTHORIZATION, CONTENT_ENCODING, CONTENT_TYPE, USER_AGENT};
+
+// Prometheus remote_write 1.0 messages, only the fields used by tsample.
+// https://prometheus.io/docs/concepts/remote_write_spec/
+
+#[derive(Clone, PartialEq, Message)]
+pub struct WriteRequest {
+    #[prost(message, repeated, tag = "1")]
+    pub timeseries: Vec<TimeSeries>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct TimeSeries {
+    // sorted by name, "__name__" is the metric name.
+    #[prost(message, repeated, tag = "1")]
+    pub labels: Vec<Label>,
+    #[prost(message, repeated, tag = "2")]
+    pub samples: Vec<Sample>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Label {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub value: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Sample {
+    #[prost(double, tag = "1")]
+    pub value: f64,
+    // milliseconds since epoch.
+    #[prost(int64, tag = "2")]
+    pub timestamp: i64,
+}
+
+/// Converts one `WriteSpec` into one gauge per numeric field, named `{measurement}_{field}`
+/// and labeled by the tags, like the other gauges of the Prometheus endpoint.
+/// `ResponseTime`, a histogram labeled by `Service` on the endpoint, is sent as the gauge
+/// `{measurement}_ResponseTime` in milliseconds.
+pub fn to_time_series(spec: &WriteSpec) -> Result<Vec<TimeSeries>, String> {
+    let labels = spec_labels(spec)?;
+    let timestamp = timestamp_in(spec.timestamp, "ms");
+
+    let mut result = vec![];
+    for (field, value) in spec.fields.iter() {
+        let mut value = match gauge_value(value) {
+            Some(value) if value.is_finite() => value,
+            _ => continue,
+        };
+        if field == "ResponseTime" {
+            value /= 1000000.0;
+        }
+        let mut series_labels = Vec::with_capacity(labels.len() + 1);
+        series_labels.push(Label {
+            name: "__name__".to_string(),
+            value: metric_name(&spec.measurement, field),
+        });
+        for (name, value) in labels.iter() {
+            series_labels.push(Label {
+                name: name.clone(),
+                value: value.clone(),
+            });
+        }
+        // "__name__" doesn't always sort first, upper case letters come before '_'.
+        series_labels.sort_by(|a, b| a.name.cmp(&b.name));
+        result.push(TimeSeries {
+            labels: series_labels,
+            samples: vec![Sample { value, timestamp }],
+        });
+    }
+    Ok(result)
+}
+
+/// Encodes the request as snappy compressed protobuf (snappy block format, not framed).
+pub fn encode_request(request: &WriteRequest) -> anyhow::Result<Vec<u8>> {
+    let body = request.encode_to_vec();
+    Ok(snap::raw::Encoder::new().compress_vec(&body)?)
+}
+
+pub struct RemoteWriter {
+    client: reqwest::Client,
+    url: String,
+    basic_auth: Option<(String, String)>,
+    bearer_token: Option<String>,
+    headers: Vec<(String, String)>,
+    batch_size: usize,
+    flush_interval: Duration,
+    max_retries: u32,
+    retry_backoff: Duration,
+    timeseries: Vec<TimeSeries>,
+    last_flush: Instant,
+    // measurements already reported as not convertible.
+    reported: HashSet<String>,
+}
+
+#[async_trait]
+impl Sink for RemoteWriter {
+    async fn write(&mut self, write_specs: &[WriteSpec]) -> anyhow::Result<usize> {
+        self.push(write_specs);
+        if self.is_full() {
+            return Ok(self.flush().await?);
+        }
+        Ok(0)
+    }
+
+    async fn tick(&mut self) -> anyhow::Result<usize> {
+        if self.is_due() {
+            return Ok(self.flush().await?);
+        }
+        Ok(0)
+    }
+
+    fn tick_interval(&self) -> Option<Duration> {
+        Some(self.flush_interval)
+    }
+
+    async fn close(&mut self) -> anyhow::Result<usize> {
+        Ok(self.flush().await?)
+    }
+}
+
+impl RemoteWriter {
+    pub fn new(config: &ExportToRemoteWrite) -> anyhow::Result<Self> {
+        log::info!("remote write service launched, url:{}", config.url);
+        url::Url::parse(&config.url)?;
+        let basic_auth = match (config.username.as_ref(), config.password.as_ref()) {
+            (Some(username), Some(password)) => Some((username.clone(), password.clone())),
+            (None, None) => None,
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "remote write needs both username and password for basic authentication"
+                ))
+            }
+        };
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(config.timeout.max(1)))
+            .build()?;
+
+        Ok(RemoteWriter {
+            client,
+            url: config.url.clone(),
+            basic_auth,
+            bearer_token: config.bearer_token.clone(),
+            headers: config
+                .headers
+                .iter()
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect(),
+            batch_size: config.batch_size.max(1),
+            flush_interval: Duration::from_millis(config.flush_interval.max(1)),
+            max_retries: config.max_retries,
+            retry_backoff: Duration::from_millis(config.retry_backoff.max(1)),
+            timeseries: vec![],
+            last_flush: Instant::now(),
+            reported: HashSet::new(),
+        })
+    }
+
+    fn push(&mut self, write_specs: &[WriteSpec]) {
+        for spec in write_specs {
+            match to_time_series(spec) {
+                Ok(timeseries) => self.timeseries.extend(timeseries),
+                Err(e) => {
+                    if self.reported.insert(spec.measurement.clone()) {
+                        log::warn!("remote write skipped {}:{}", spec.measurement, e);
+                    }
+                }
+            }
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.timeseries.len() >= self.batch_size
+    }
+
+    fn is_due(&self) -> bool {
+        !self.timeseries.is_empty() && self.last_flush.elapsed() >= self.flush_interval
+    }
+
+    /// Writes the buffered series, returns the number of series written.
+    async fn flush(&mut self) -> Result<usize, FlushError> {
+        self.last_flush = Instant::now();
+        if self.timeseries.is_empty() {
+            return Ok(0);
+        }
+        let request = WriteRequest {
+            timeseries: std::mem::take(&mut self.timeseries),
+        };
+        let samples = request.timeseries.len();
+        let body = match encode_request(&request) {
+            Ok(body) => body,
+            Err(e) => {
+                return Err(FlushError::new(
+                    samples,
+                    e.context("remote write encode error"),
+                ))
+            }
+        };
+
+        let mut backoff = self.retry_backoff;
+        let mut attempt = 0;
+        loop {
+            match self.send(body.clone()).await {
+                Ok(_) => {
+                    log::debug!("{} sample(s) written to remote write", samples);
+                    return Ok(samples);
+                }
+                Err(WriteError::Retry(e)) if attempt < self.max_retries => {
+                    attempt += 1;
+                    log::warn!(
+                        "remote write error, retry {}/{} in {:?}:{:?}",
+                        attempt,
+                        self.max_retries,
+                        backoff,
+                        e
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+                Err(WriteError::Retry(e)) | Err(WriteError::Reject(e)) => {
+                    return Err(FlushError::new(samples, e.context("remote write error")));
+                }
+            }
+        }
+    }
+
+    async fn send(&self, body: Vec<u8>) -> Result<(), WriteError> {
+        let mut request = self
+            .client
+            .post(&self.url)
+            .header(CONTENT_TYPE, "application/x-protobuf")
+            .header(CONTENT_ENCODING, "snappy")
+            .header("X-Prometheus-Remote-Write-Version", "0.1.0")
+            .header(USER_AGENT, concat!("tsample/", env!("CARGO_PKG_VERSION")))
+            .body(body);
+        for (name, value) in self.headers.iter() {
+            request = request.header(name, value);
+        }
+        if let Some((ref username, ref password)) = self.basic_auth {
+            request = request.basic_auth(username, Some(password));
+        }
+        if let Some(ref token) = self.bearer_token {
+            request = request.header(AUTHORIZATION, format!("Bearer {}", token));
+        }
+        let res = match request.send().await {
+            Ok(res) => res,
+            Err(e) => return Err(WriteError::Retry(e.into())),
+        };
+        let status = res.status();
+        if !status.is_success() {
+            let text = res.text().await.unwrap_or_default();
+            let e = anyhow::anyhow!("remote write failed, status:{}, response:{}", status, text);
+            // other 4xx responses mean the samples are not accepted, sending them again won't help.
+            if status.is_client_error() && status != reqwest::StatusCode::TOO_MANY_REQUESTS {
+                return Err(WriteError::Reject(e));
+            }
+            return Err(WriteError::Retry(e));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use influxdb::{Timestamp, Type};
+
+    #[test]
+    fn test_to_time_series() {
+        let spec = WriteSpec::new(Timestamp::Milliseconds(1683600000123), "ConnectionServer")
+            .add_tag("Platform", Type::Text("p1".to_string()))
+            .add_tag("cxserver", Type::Text("".to_string()))
+            .add_tag("sub_name", Type::Text("cx 1".to_string()))
+            .add_field("ResponseTime", Type::SignedInteger(2000000))
+            .add_field("desc", Type::Text("skipped".to_string()));
+
+        let timeseries = to_time_series(&spec).unwrap();
+        assert_eq!(timeseries.len(), 1);
+        let labels: Vec<(&str, &str)> = timeseries[0]
+            .labels
+            .iter()
+            .map(|l| (l.name.as_str(), l.value.as_str()))
+            .collect();
+        assert_eq!(
+            labels,
+            vec![
+                ("Platform", "p1"),
+                ("__name__", "ConnectionServer_ResponseTime"),
+                ("sub_name", "cx 1"),
+            ]
+        );
+        assert_eq!(
+            timeseries[0].samples,
+            vec![Sample {
+                value: 2.0,
+                timestamp: 1683600000123
+            }]
+        );
+    }
+
+    #[test]
+    fn test_encode_request() {
+        let spec = WriteSpec::new(Timestamp::Milliseconds(0), "m").add_field("v", Type::Float(1.5));
+        let request = WriteRequest {
+            timeseries: to_time_series(&spec).unwrap(),
+        };
+        let body = encode_request(&request).unwrap();
+        let decoded = snap::raw::Decoder::new().decompress_vec(&body).unwrap();
+        assert_eq!(WriteRequest::decode(decoded.as_slice()).unwrap(), request);
+    }
+}
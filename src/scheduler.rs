@@ -0,0 +1,446 @@
+use std::{collections::HashMap, time::Duration};
+
+use crate::jmxquery::{repeated_jmx_query, JmxObjectNameList};
+use crate::selfmetrics::{CYCLE_DURATION, SCRAPE_OVERRUNS};
+use crate::spec::WriteSpec;
+use crate::testconfig::{ArbitraryMetric, Schedule, SubSystem, TestConfig, ThingworxServer};
+use crate::twxquery::{
+    repeated_arbitrary_query, repeated_connection_server_query, repeated_subsystem_query,
+};
+use rand::Rng;
+use tokio::{sync::mpsc::Sender, task::JoinHandle, time::Instant};
+
+// (names, metrics) of the connection servers of each ThingWorx server.
+pub type ConnectionServerCache = evmap::ReadHandle<String, (Vec<String>, Vec<String>)>;
+pub type JmxCache = evmap::ReadHandle<String, JmxObjectNameList>;
+
+/// One thing queried on its own timer.
+#[derive(Debug, Clone, PartialEq)]
+enum Source {
+    Subsystem(SubSystem),
+    ConnectionServer { name: String, metrics: Vec<String> },
+    // the JMX group name, its object names are refreshed in the background.
+    Jmx(String),
+    Arbitrary(ArbitraryMetric),
+}
+
+impl Source {
+    fn kind(&self) -> &'static str {
+        match self {
+            Source::Subsystem(_) => "subsystem",
+            Source::ConnectionServer { .. } => "cxserver",
+            Source::Jmx(_) => "jmx",
+            Source::Arbitrary(_) => "arbitrary",
+        }
+    }
+
+    fn target(&self) -> &str {
+        match self {
+            Source::Subsystem(subsystem) => &subsystem.name,
+            Source::ConnectionServer { name, .. } => name,
+            Source::Jmx(name) => name,
+            Source::Arbitrary(am) => &am.name,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct SourceKey {
+    server: String,
+    kind: &'static str,
+    target: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Timing {
+    interval: Duration,
+    offset: Duration,
+    jitter: Duration,
+}
+
+impl Timing {
+    fn new(tc: &TestConfig, server: &ThingworxServer, schedule: &Schedule) -> Self {
+        let interval = schedule
+            .interval
+            .or(server.scrap_interval)
+            .unwrap_or(tc.scrap_interval);
+        Timing {
+            interval: Duration::from_secs(interval.max(1)),
+            offset: Duration::from_secs(schedule.offset.unwrap_or(0)),
+            jitter: Duration::from_secs(server.scrap_jitter.unwrap_or(tc.scrap_jitter)),
+        }
+    }
+
+    /// Moves `next` to the next run after `now`, returns the number of skipped runs.
+    /// The run started `jitter` after `next`, the time it took is counted from there.
+    fn advance(&self, next: &mut Instant, jitter: Duration, now: Instant) -> u32 {
+        let now = now.checked_sub(jitter).unwrap_or(now);
+        *next += self.interval;
+        if now <= *next {
+            return 0;
+        }
+        let skipped = ((now - *next).as_nanos() / self.interval.as_nanos()) as u32 + 1;
+        *next += self.interval * skipped;
+        skipped
+    }
+}
+
+/// The longest interval between two queries of a configured source, the subsystems,
+/// connection servers, JMX groups and arbitrary metrics can be queried less often than
+/// the global `scrap_interval`.
+pub fn longest_interval(tc: &TestConfig) -> Duration {
+    let mut longest = Duration::from_secs(tc.scrap_interval.max(1));
+    for server in tc.thingworx_servers.iter() {
+        for interval in source_intervals(tc, server) {
+            longest = longest.max(interval);
+        }
+    }
+    longest
+}
+
+/// The interval of every enabled source configured for the server.
+pub fn source_intervals(tc: &TestConfig, server: &ThingworxServer) -> Vec<Duration> {
+    let subsystems = server
+        .subsystems
+        .iter()
+        .filter(|s| s.enabled)
+        .map(|s| &s.schedule);
+    let cxservers = server.connection_servers.iter().map(|c| &c.schedule);
+    let jmx = server.jmx_metrics.iter().flatten().map(|j| &j.schedule);
+    let arbitrary = server
+        .arbitrary_metrics
+        .iter()
+        .flatten()
+        .filter(|am| am.enabled)
+        .map(|am| &am.schedule);
+    subsystems
+        .chain(cxservers)
+        .chain(jmx)
+        .chain(arbitrary)
+        .map(|schedule| Timing::new(tc, server, schedule).interval)
+        .collect()
+}
+
+/// What a task runs, it's restarted when any of it changes.
+#[derive(Debug, Clone, PartialEq)]
+struct TaskSettings {
+    server: ThingworxServer,
+    source: Source,
+    timing: Timing,
+    query_timeout: u64,
+}
+
+struct ScheduledTask {
+    settings: TaskSettings,
+    task: JoinHandle<()>,
+}
+
+/// Runs one task per source of every server, and keeps the tasks in line with the
+/// configuration and the connection servers and JMX groups found by the refreshes.
+pub struct Scheduler {
+    sender: Sender<Vec<WriteSpec>>,
+    cxserver_cache: ConnectionServerCache,
+    jmx_cache: JmxCache,
+    tasks: HashMap<SourceKey, ScheduledTask>,
+}
+
+impl Scheduler {
+    pub fn new(
+        sender: Sender<Vec<WriteSpec>>,
+        cxserver_cache: ConnectionServerCache,
+        jmx_cache: JmxCache,
+    ) -> Self {
+        Scheduler {
+            sender,
+            cxserver_cache,
+            jmx_cache,
+            tasks: HashMap::new(),
+        }
+    }
+
+    /// Starts the tasks of the new sources, stops the tasks of the removed ones and
+    /// restarts the tasks whose source, server or timing changed.
+    pub fn reconcile(&mut self, tc: &TestConfig) {
+        let mut desired = HashMap::new();
+        for server in tc.thingworx_servers.iter() {
+            for (source, schedule) in self.sources(server) {
+                let key = SourceKey {
+                    server: server.name.clone(),
+                    kind: source.kind(),
+                    target: source.target().to_string(),
+                };
+                let settings = TaskSettings {
+                    server: query_settings(server),
+                    source,
+                    timing: Timing::new(tc, server, &schedule),
+                    query_timeout: tc.query_time_out,
+                };
+                desired.insert(key, settings);
+            }
+        }
+
+        self.tasks.retain(|key, scheduled| {
+            let keep = desired
+                .get(key)
+                .map_or(false, |settings| *settings == scheduled.settings);
+            if !keep {
+                scheduled.task.abort();
+                log::info!(
+                    "{} query stopped, server:{}, target:{}",
+                    key.kind,
+                    key.server,
+                    key.target
+                );
+            }
+            keep
+        });
+
+        for (key, settings) in desired {
+            if self.tasks.contains_key(&key) {
+                continue;
+            }
+            log::info!(
+                "{} query scheduled every {:?}, server:{}, target:{}",
+                key.kind,
+                settings.timing.interval,
+                key.server,
+                key.target
+            );
+            let task = tokio::spawn(run_source(
+                settings.server.clone(),
+                settings.source.clone(),
+                settings.timing,
+                self.sender.clone(),
+                settings.query_timeout,
+                self.jmx_cache.clone(),
+            ));
+            self.tasks.insert(key, ScheduledTask { settings, task });
+        }
+    }
+
+    fn sources(&self, server: &ThingworxServer) -> Vec<(Source, Schedule)> {
+        let mut sources = vec![];
+        for subsystem in server.subsystems.iter().filter(|s| s.enabled) {
+            sources.push((
+                Source::Subsystem(subsystem.clone()),
+                subsystem.schedule.clone(),
+            ));
+        }
+        if let Some(ref cxserver_config) = server.connection_servers {
+            if let Some(cache) = self.cxserver_cache.get_one(&server.name) {
+                for name in cache.0.iter() {
+                    sources.push((
+                        Source::ConnectionServer {
+                            name: name.clone(),
+                            metrics: cache.1.clone(),
+                        },
+                        cxserver_config.schedule.clone(),
+                    ));
+                }
+            }
+        }
+        if let Some(ref jmx_config) = server.jmx_metrics {
+            for jmx_metric in jmx_config.iter() {
+                sources.push((
+                    Source::Jmx(jmx_metric.name.clone()),
+                    jmx_metric.schedule.clone(),
+                ));
+            }
+        }
+        if let Some(ref arbitrary_config) = server.arbitrary_metrics {
+            for am in arbitrary_config.iter().filter(|am| am.enabled) {
+                sources.push((Source::Arbitrary(am.clone()), am.schedule.clone()));
+            }
+        }
+        sources
+    }
+
+    /// Stops all the tasks, a query in progress is abandoned.
+    pub async fn stop(self) {
+        for (_, scheduled) in self.tasks {
+            scheduled.task.abort();
+            let _ = scheduled.task.await;
+        }
+    }
+}
+
+/// The server without its sources, so a task isn't restarted when another source of its
+/// server is added or removed.
+fn query_settings(server: &ThingworxServer) -> ThingworxServer {
+    ThingworxServer {
+        subsystems: vec![],
+        connection_servers: None,
+        jmx_metrics: None,
+        arbitrary_metrics: None,
+        ..server.clone()
+    }
+}
+
+async fn run_source(
+    server: ThingworxServer,
+    source: Source,
+    timing: Timing,
+    sender: Sender<Vec<WriteSpec>>,
+    query_timeout: u64,
+    jmx_cache: JmxCache,
+) {
+    let (kind, target) = (source.kind(), source.target().to_string());
+    let mut next = Instant::now() + timing.offset;
+    loop {
+        let jitter = if timing.jitter.is_zero() {
+            Duration::ZERO
+        } else {
+            rand::thread_rng().gen_range(Duration::ZERO..=timing.jitter)
+        };
+        tokio::time::sleep_until(next + jitter).await;
+
+        let started = Instant::now();
+        let result = match source {
+            Source::Subsystem(ref subsystem) => {
+                repeated_subsystem_query(&server, subsystem, sender.clone(), query_timeout).await
+            }
+            Source::ConnectionServer {
+                ref name,
+                ref metrics,
+            } => {
+                repeated_connection_server_query(
+                    &server,
+                    name,
+                    metrics.clone(),
+                    sender.clone(),
+                    query_timeout,
+                )
+                .await
+            }
+            Source::Jmx(ref name) => {
+                let group = jmx_cache.get_one(&server.name).and_then(|cache| {
+                    cache
+                        .iter()
+                        .find(|(measurement, ..)| measurement == name)
+                        .cloned()
+                });
+                match group {
+                    Some((measurement, object_name_list, name_alternative, metrics)) => {
+                        repeated_jmx_query(
+                            &server,
+                            measurement,
+                            object_name_list,
+                            name_alternative,
+                            metrics,
+                            sender.clone(),
+                            query_timeout,
+                        )
+                        .await
+                    }
+                    None => {
+                        log::debug!("JMX object names of {} are not refreshed yet", name);
+                        Ok(())
+                    }
+                }
+            }
+            Source::Arbitrary(ref am) => {
+                repeated_arbitrary_query(&server, am.clone(), sender.clone(), query_timeout).await
+            }
+        };
+        if let Err(e) = result {
+            log::error!("{} query error, target:{}, error:{:?}", kind, target, e);
+        }
+        let spent_time = started.elapsed();
+        CYCLE_DURATION
+            .with_label_values(&[&server.name, kind])
+            .observe(spent_time.as_secs_f64());
+
+        let skipped = timing.advance(&mut next, jitter, Instant::now());
+        if skipped > 0 {
+            SCRAPE_OVERRUNS
+                .with_label_values(&[&server.name, kind, &target])
+                .inc();
+            log::warn!(
+                "{} query of {} took {:?}, longer than its interval {:?}, {} run(s) skipped",
+                kind,
+                target,
+                spent_time,
+                timing.interval,
+                skipped
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_longest_interval() {
+        let tc: TestConfig = serde_yaml::from_str(
+            "
+scrap_interval: 30
+export_to_influxdb: {enabled: false, server_name: localhost}
+thingworx_servers:
+  - {name: p1, host: localhost, port: 8080, app_key: key,
+     subsystems: [{name: ValueStreamProcessingSubsystem, interval: 300},
+                  {name: StreamProcessingSubsystem, interval: 900, enabled: false}]}
+  - {name: p2, host: localhost, port: 8080, app_key: key, scrap_interval: 120,
+     subsystems: [{name: EventProcessingSubsystem}]}
+",
+        )
+        .unwrap();
+        assert_eq!(
+            source_intervals(&tc, &tc.thingworx_servers[1]),
+            vec![Duration::from_secs(120)]
+        );
+        // the disabled subsystem doesn't count, the Prometheus series of the subsystem
+        // queried every 300s are kept for stale_series_cycles x 300s.
+        assert_eq!(longest_interval(&tc), Duration::from_secs(300));
+    }
+
+    #[test]
+    fn test_timing() {
+        let tc: TestConfig = serde_yaml::from_str(
+            "
+scrap_interval: 30
+scrap_jitter: 2
+export_to_influxdb: {enabled: false, server_name: localhost}
+thingworx_servers:
+  - {name: p1, host: localhost, port: 8080, app_key: key, scrap_interval: 10,
+     subsystems: [{name: ValueStreamProcessingSubsystem, interval: 5, offset: 1},
+                  {name: EventProcessingSubsystem}]}
+",
+        )
+        .unwrap();
+        let server = &tc.thingworx_servers[0];
+        let timing = Timing::new(&tc, server, &server.subsystems[0].schedule);
+        assert_eq!(timing.interval, Duration::from_secs(5));
+        assert_eq!(timing.offset, Duration::from_secs(1));
+        assert_eq!(timing.jitter, Duration::from_secs(2));
+        let timing = Timing::new(&tc, server, &server.subsystems[1].schedule);
+        assert_eq!(timing.interval, Duration::from_secs(10));
+
+        // a run of 25 seconds misses the runs at 10 and 20 seconds.
+        let start = Instant::now();
+        let mut next = start;
+        assert_eq!(
+            timing.advance(&mut next, Duration::ZERO, start + Duration::from_secs(3)),
+            0
+        );
+        assert_eq!(next, start + Duration::from_secs(10));
+        let mut next = start;
+        assert_eq!(
+            timing.advance(&mut next, Duration::ZERO, start + Duration::from_secs(25)),
+            2
+        );
+        assert_eq!(next, start + Duration::from_secs(30));
+        // started 2 seconds late by the jitter, a run of 9 seconds isn't an overrun.
+        let mut next = start;
+        assert_eq!(
+            timing.advance(
+                &mut next,
+                Duration::from_secs(2),
+                start + Duration::from_secs(11)
+            ),
+            0
+        );
+        assert_eq!(next, start + Duration::from_secs(10));
+    }
+}
@@ -0,0 +1,184 @@
+use std::{env, fs, path::Path};
+
+use anyhow::Context;
+use serde_yaml::Value;
+
+// the values of these keys are replaced in the flattened configuration.
+const SECRET_KEYS: &[&str] = &[
+    "app_key",
+    "password",
+    "token",
+    "bearer_token",
+    "client_secret",
+    "authorization",
+    "x-api-key",
+];
+const REDACTED: &str = "<redacted>";
+
+/// Resolves the references in all the string values of the configuration, before it's
+/// deserialized: `${ENV_VAR}`, `${ENV_VAR:-default}` when the variable is unset or empty,
+/// and `${file:/run/secrets/x}` for the content of a file without its trailing newline,
+/// a relative path being relative to the directory of `file`, the configuration file of
+/// the value. `$${` is kept as a literal `${`.
+pub fn interpolate(value: &mut Value, file: &Path) -> anyhow::Result<()> {
+    match unresolved(value, file).into_iter().next() {
+        Some((path, error)) => Err(error.context(format!("failed to resolve {}", path))),
+        None => Ok(()),
+    }
+}
+
+/// Resolves the references like `interpolate`, and returns the path of every value with a
+/// reference that can't be resolved. Those values are left unchanged.
+pub fn unresolved(value: &mut Value, file: &Path) -> Vec<(String, anyhow::Error)> {
+    let base = file.parent().unwrap_or(Path::new(""));
+    let mut errors = vec![];
+    interpolate_at(value, "", base, &mut errors);
+    errors
+}
+
+fn interpolate_at(
+    value: &mut Value,
+    path: &str,
+    base: &Path,
+    errors: &mut Vec<(String, anyhow::Error)>,
+) {
+    match value {
+        Value::String(text) if text.contains("${") => match interpolate_str(text, base) {
+            Ok(resolved) => *text = resolved,
+            Err(e) => errors.push((path.to_string(), e)),
+        },
+        Value::Sequence(items) => {
+            for (index, item) in items.iter_mut().enumerate() {
+                interpolate_at(item, &format!("{}[{}]", path, index), base, errors);
+            }
+        }
+        Value::Mapping(mapping) => {
+            for (key, item) in mapping.iter_mut() {
+                let key = key.as_str().unwrap_or("?");
+                let path = if path.is_empty() {
+                    key.to_string()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+                interpolate_at(item, &path, base, errors);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn interpolate_str(input: &str, base: &Path) -> anyhow::Result<String> {
+    let mut output = String::with_capacity(input.len());
+    let mut rest = input;
+    while let Some(start) = rest.find('$') {
+        output.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(after) = rest.strip_prefix("$${") {
+            output.push_str("${");
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("${") {
+            let end = after
+                .find('}')
+                .ok_or_else(|| anyhow::anyhow!("unclosed reference in:{}", input))?;
+            output.push_str(&resolve(&after[..end], base)?);
+            rest = &after[end + 1..];
+        } else {
+            output.push('$');
+            rest = &rest[1..];
+        }
+    }
+    output.push_str(rest);
+    Ok(output)
+}
+
+fn resolve(reference: &str, base: &Path) -> anyhow::Result<String> {
+    if let Some(path) = reference.strip_prefix("file:") {
+        let path = base.join(path);
+        let content = fs::read_to_string(&path)
+            .with_context(|| format!("failed to read file:{}", path.display()))?;
+        return Ok(content.trim_end_matches(['\r', '\n']).to_string());
+    }
+    let (name, default) = match reference.split_once(":-") {
+        Some((name, default)) => (name, Some(default)),
+        None => (reference, None),
+    };
+    match (env::var(name), default) {
+        (Ok(value), Some(default)) if value.is_empty() => Ok(default.to_string()),
+        (Ok(value), _) => Ok(value),
+        (Err(_), Some(default)) => Ok(default.to_string()),
+        (Err(_), None) => Err(anyhow::anyhow!("environment variable {} is not set", name)),
+    }
+}
+
+/// Replaces the app keys, passwords, tokens and client secrets, so the configuration can be shared.
+pub fn redact(value: &mut Value) {
+    match value {
+        Value::Sequence(items) => items.iter_mut().for_each(redact),
+        Value::Mapping(mapping) => {
+            for (key, item) in mapping.iter_mut() {
+                let secret = key.as_str().map_or(false, |key| {
+                    SECRET_KEYS.contains(&key.to_lowercase().as_str())
+                });
+                match item {
+                    Value::String(text) if secret && !text.is_empty() => {
+                        *text = REDACTED.to_string()
+                    }
+                    _ => redact(item),
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interpolate_and_redact() {
+        env::set_var("TSAMPLE_TEST_APP_KEY", "key-from-env");
+        env::remove_var("TSAMPLE_TEST_UNSET");
+        let directory = env::temp_dir().join(format!("tsample-secrets-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let secret_file = directory.join("secret");
+        let config_file = directory.join("config.yaml");
+        fs::write(&secret_file, "secret-from-file\n").unwrap();
+
+        let mut document: Value = serde_yaml::from_str(&format!(
+            "
+app_key: ${{TSAMPLE_TEST_APP_KEY}}
+password: ${{file:{}}}
+client_secret: ${{file:secret}}
+servers:
+  - url: http://${{TSAMPLE_TEST_UNSET:-localhost}}:8080/$${{literal}}
+    port: 8080
+",
+            secret_file.display()
+        ))
+        .unwrap();
+        interpolate(&mut document, &config_file).unwrap();
+        assert_eq!(document["app_key"].as_str(), Some("key-from-env"));
+        assert_eq!(document["password"].as_str(), Some("secret-from-file"));
+        // relative to the directory of the configuration file.
+        assert_eq!(document["client_secret"].as_str(), Some("secret-from-file"));
+        assert_eq!(
+            document["servers"][0]["url"].as_str(),
+            Some("http://localhost:8080/${literal}")
+        );
+
+        redact(&mut document);
+        assert_eq!(document["app_key"].as_str(), Some(REDACTED));
+        assert_eq!(document["password"].as_str(), Some(REDACTED));
+        assert_eq!(document["servers"][0]["port"].as_u64(), Some(8080));
+        fs::remove_dir_all(&directory).unwrap();
+
+        let mut document: Value =
+            serde_yaml::from_str("servers: [{app_key: '${TSAMPLE_TEST_UNSET}'}]").unwrap();
+        let error = interpolate(&mut document, &config_file).unwrap_err();
+        assert_eq!(
+            format!("{:#}", error),
+            "failed to resolve servers[0].app_key: environment variable TSAMPLE_TEST_UNSET is not set"
+        );
+    }
+}
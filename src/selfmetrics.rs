@@ -0,0 +1,516 @@
+use std::time::{Duration, Instant};
+
+use crate::prometheus::REGISTRY;
+use crate::reload::ConfigReceiver;
+use crate::spec::WriteSpec;
+use crate::testconfig::TestConfig;
+use influxdb::{Timestamp, Type};
+use lazy_static::lazy_static;
+use prometheus::{
+    core::Collector, proto::MetricType, HistogramOpts, HistogramVec, IntCounter, IntCounterVec,
+    IntGauge, IntGaugeVec, Opts,
+};
+use reqwest::StatusCode;
+use tokio::sync::mpsc::Sender;
+
+// metrics about tsample itself, exposed with the other Prometheus metrics and
+// optionally exported like the collected points as the `tsample_internal` measurement.
+
+pub const INTERNAL_MEASUREMENT: &str = "tsample_internal";
+// one point per query: up, http_status, error and duration_seconds.
+pub const STATUS_MEASUREMENT: &str = "tsample_target";
+
+const DURATION_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0,
+];
+
+lazy_static! {
+    pub static ref SCRAPES: IntCounterVec = register(
+        IntCounterVec::new(
+            Opts::new("tsample_scrapes_total", "Queries sent to the monitored targets."),
+            &["platform", "kind", "target"],
+        )
+        .unwrap()
+    );
+    pub static ref SCRAPE_FAILURES: IntCounterVec = register(
+        IntCounterVec::new(
+            Opts::new(
+                "tsample_scrape_failures_total",
+                "Queries that returned no metrics because of an error."
+            ),
+            &["platform", "kind", "target"],
+        )
+        .unwrap()
+    );
+    pub static ref SCRAPE_DURATION: HistogramVec = register(
+        HistogramVec::new(
+            HistogramOpts::new("tsample_scrape_duration_seconds", "Duration of one query.")
+                .buckets(DURATION_BUCKETS.to_vec()),
+            &["platform", "kind"],
+        )
+        .unwrap()
+    );
+    pub static ref CYCLE_DURATION: HistogramVec = register(
+        HistogramVec::new(
+            HistogramOpts::new(
+                "tsample_cycle_duration_seconds",
+                "Duration of one scheduled run of a source."
+            )
+            .buckets(DURATION_BUCKETS.to_vec()),
+            &["platform", "kind"],
+        )
+        .unwrap()
+    );
+    pub static ref SCRAPE_OVERRUNS: IntCounterVec = register(
+        IntCounterVec::new(
+            Opts::new(
+                "tsample_scrape_overruns_total",
+                "Runs of a source that took longer than its interval, the missed runs are skipped."
+            ),
+            &["platform", "kind", "target"],
+        )
+        .unwrap()
+    );
+    pub static ref SCRAPE_RETRIES: IntCounterVec = register(
+        IntCounterVec::new(
+            Opts::new(
+                "tsample_scrape_retries_total",
+                "Queries sent again after a timeout, a connection error or a 429, 502, 503 or 504 status."
+            ),
+            &["platform", "kind", "target"],
+        )
+        .unwrap()
+    );
+    pub static ref CIRCUIT_STATE: IntGaugeVec = register(
+        IntGaugeVec::new(
+            Opts::new(
+                "tsample_circuit_breaker_state",
+                "State of the circuit breaker of the server: 0 closed, 1 open, 2 half open."
+            ),
+            &["platform"],
+        )
+        .unwrap()
+    );
+    pub static ref CIRCUIT_TRANSITIONS: IntCounterVec = register(
+        IntCounterVec::new(
+            Opts::new(
+                "tsample_circuit_breaker_transitions_total",
+                "Changes of the circuit breaker of the server, by new state."
+            ),
+            &["platform", "state"],
+        )
+        .unwrap()
+    );
+    pub static ref CONFIG_RELOADS: IntCounterVec = register(
+        IntCounterVec::new(
+            Opts::new(
+                "tsample_config_reloads_total",
+                "Configuration reloads, applied (success) or rejected (failure)."
+            ),
+            &["result"],
+        )
+        .unwrap()
+    );
+    pub static ref COLLECTOR_QUEUE_DEPTH: IntGauge = register(
+        IntGauge::new(
+            "tsample_collector_queue_depth",
+            "Batches waiting to be dispatched to the sinks, sampled every second."
+        )
+        .unwrap()
+    );
+    pub static ref SINK_BATCHES: IntCounterVec = register(
+        IntCounterVec::new(
+            Opts::new("tsample_sink_batches_total", "Batches written by the sink."),
+            &["sink"],
+        )
+        .unwrap()
+    );
+    pub static ref SINK_POINTS: IntCounterVec = register(
+        IntCounterVec::new(
+            Opts::new("tsample_sink_points_total", "Points written by the sink."),
+            &["sink"],
+        )
+        .unwrap()
+    );
+    pub static ref SINK_ERRORS: IntCounterVec = register(
+        IntCounterVec::new(
+            Opts::new(
+                "tsample_sink_errors_total",
+                "Failed writes, ticks and creations of the sink."
+            ),
+            &["sink"],
+        )
+        .unwrap()
+    );
+    pub static ref SINK_DROPPED: IntCounterVec = register(
+        IntCounterVec::new(
+            Opts::new(
+                "tsample_sink_dropped_points_total",
+                "Points not delivered because the sink queue was full, the sink stopped or it lost them."
+            ),
+            &["sink"],
+        )
+        .unwrap()
+    );
+    pub static ref SINK_QUEUE_DEPTH: IntGaugeVec = register(
+        IntGaugeVec::new(
+            Opts::new("tsample_sink_queue_depth", "Batches waiting in the sink queue."),
+            &["sink"],
+        )
+        .unwrap()
+    );
+    pub static ref SINK_WRITE_DURATION: HistogramVec = register(
+        HistogramVec::new(
+            HistogramOpts::new(
+                "tsample_sink_write_duration_seconds",
+                "Duration of one batch write."
+            )
+            .buckets(DURATION_BUCKETS.to_vec()),
+            &["sink"],
+        )
+        .unwrap()
+    );
+    pub static ref PROMETHEUS_CONFLICTS: IntCounter = register(
+        IntCounter::new(
+            "tsample_prometheus_conflicts_total",
+            "Points of a field skipped by the Prometheus endpoint, its metric name or labels conflict with another one."
+        )
+        .unwrap()
+    );
+    pub static ref SPOOL_POINTS: IntCounterVec = register(
+        IntCounterVec::new(
+            Opts::new(
+                "tsample_spool_points_total",
+                "Points moved into the spool (spooled), removed by the limits (dropped) or written from it (replayed)."
+            ),
+            &["state"],
+        )
+        .unwrap()
+    );
+}
+
+fn register<C: Collector + Clone + 'static>(collector: C) -> C {
+    if let Err(e) = REGISTRY.register(Box::new(collector.clone())) {
+        log::error!("failed to register internal metric:{:?}", e);
+    }
+    collector
+}
+
+/// Registers the internal metrics, so they are exposed before their first update.
+pub fn init() {
+    lazy_static::initialize(&SCRAPES);
+    lazy_static::initialize(&SCRAPE_FAILURES);
+    lazy_static::initialize(&SCRAPE_DURATION);
+    lazy_static::initialize(&CYCLE_DURATION);
+    lazy_static::initialize(&SCRAPE_OVERRUNS);
+    lazy_static::initialize(&SCRAPE_RETRIES);
+    lazy_static::initialize(&CIRCUIT_STATE);
+    lazy_static::initialize(&CIRCUIT_TRANSITIONS);
+    lazy_static::initialize(&CONFIG_RELOADS);
+    lazy_static::initialize(&COLLECTOR_QUEUE_DEPTH);
+    lazy_static::initialize(&SINK_BATCHES);
+    lazy_static::initialize(&SINK_POINTS);
+    lazy_static::initialize(&SINK_ERRORS);
+    lazy_static::initialize(&SINK_DROPPED);
+    lazy_static::initialize(&SINK_QUEUE_DEPTH);
+    lazy_static::initialize(&SINK_WRITE_DURATION);
+    lazy_static::initialize(&PROMETHEUS_CONFLICTS);
+    lazy_static::initialize(&SPOOL_POINTS);
+
+    // cpu, memory, file descriptors and threads of tsample, only available on linux.
+    #[cfg(target_os = "linux")]
+    {
+        let process = prometheus::process_collector::ProcessCollector::for_self();
+        if let Err(e) = REGISTRY.register(Box::new(process)) {
+            log::error!("failed to register process metrics:{:?}", e);
+        }
+    }
+}
+
+/// One query to a target, counted in the internal metrics and reported as a status point.
+/// It's counted as failed when it's dropped before `finish`.
+pub struct Scrape {
+    platform: String,
+    kind: &'static str,
+    target: String,
+    start: Instant,
+    http_status: Option<u16>,
+    // the error class, none while the query succeeds.
+    error: Option<&'static str>,
+    finished: bool,
+}
+
+impl Scrape {
+    pub fn start(platform: &str, kind: &'static str, target: &str) -> Self {
+        SCRAPES.with_label_values(&[platform, kind, target]).inc();
+        Scrape {
+            platform: platform.to_string(),
+            kind,
+            target: target.to_string(),
+            start: Instant::now(),
+            http_status: None,
+            error: None,
+            finished: false,
+        }
+    }
+
+    /// Starts the duration again, the time waiting for the server limits is not counted.
+    pub fn restart(&mut self) {
+        self.start = Instant::now();
+    }
+
+    pub fn response(&mut self, status: StatusCode) {
+        self.http_status = Some(status.as_u16());
+        if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN {
+            self.error = Some("auth");
+        } else if !status.is_success() {
+            self.error = Some("http");
+        }
+    }
+
+    /// The credentials of the request couldn't be obtained.
+    pub fn auth_failed(&mut self) {
+        self.error = Some("auth");
+    }
+
+    pub fn http_status(&self) -> Option<u16> {
+        self.http_status
+    }
+
+    pub fn parse_error(&mut self) {
+        self.error = Some("parse");
+    }
+
+    /// The query is skipped because the circuit breaker of the server is open.
+    pub fn circuit_open(&mut self) {
+        self.error = Some("circuit_open");
+    }
+
+    pub fn failure(&self) -> Option<&'static str> {
+        self.error
+    }
+
+    /// The server didn't answer or is overloaded, the query may succeed when it's sent again.
+    pub fn is_transient(&self) -> bool {
+        match self.error {
+            Some("timeout") | Some("connect") => true,
+            Some("http") => matches!(self.http_status, Some(429 | 502 | 503 | 504)),
+            _ => false,
+        }
+    }
+
+    /// Classifies the error of a failed query, unless it's already classified.
+    pub fn error(&mut self, error: &anyhow::Error) {
+        if self.error.is_none() {
+            self.error = Some(error_class(error));
+        }
+    }
+
+    /// Records the query and returns the status point of the target.
+    pub fn finish(mut self) -> WriteSpec {
+        let duration = self.record();
+        let timestamp = Timestamp::Milliseconds(chrono::Utc::now().timestamp_millis() as u128);
+        WriteSpec::new(timestamp, STATUS_MEASUREMENT)
+            .add_tag("Platform", Type::Text(self.platform.clone()))
+            .add_tag("kind", Type::Text(self.kind.to_string()))
+            .add_tag("target", Type::Text(self.target.clone()))
+            .add_field("up", Type::SignedInteger(self.error.is_none() as i64))
+            .add_field(
+                "http_status",
+                Type::SignedInteger(self.http_status.unwrap_or(0) as i64),
+            )
+            .add_field("error", Type::Text(self.error.unwrap_or("").to_string()))
+            .add_field("duration_seconds", Type::Float(duration.as_secs_f64()))
+    }
+
+    fn record(&mut self) -> Duration {
+        let duration = self.start.elapsed();
+        if self.finished {
+            return duration;
+        }
+        self.finished = true;
+        SCRAPE_DURATION
+            .with_label_values(&[&self.platform, self.kind])
+            .observe(duration.as_secs_f64());
+        if self.error.is_some() {
+            SCRAPE_FAILURES
+                .with_label_values(&[&self.platform, self.kind, &self.target])
+                .inc();
+        }
+        duration
+    }
+}
+
+impl Drop for Scrape {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.error.get_or_insert("request");
+            self.record();
+        }
+    }
+}
+
+/// timeout, dns, tls, connect, parse or request for the other errors.
+fn error_class(error: &anyhow::Error) -> &'static str {
+    let error = match error.downcast_ref::<reqwest::Error>() {
+        Some(error) => error,
+        None => return "request",
+    };
+    if error.is_timeout() {
+        return "timeout";
+    }
+    if error.is_decode() {
+        return "parse";
+    }
+    // the cause is only known from the messages of the underlying errors.
+    let mut causes = String::new();
+    let mut source = std::error::Error::source(error);
+    while let Some(cause) = source {
+        causes.push_str(&cause.to_string().to_lowercase());
+        causes.push('\n');
+        source = cause.source();
+    }
+    if causes.contains("dns error") || causes.contains("failed to lookup address") {
+        "dns"
+    } else if causes.contains("certificate") || causes.contains("tls") {
+        "tls"
+    } else if error.is_connect() {
+        "connect"
+    } else {
+        "request"
+    }
+}
+
+/// One point per internal metric and label set, the histograms as their count and sum.
+pub fn internal_specs(timestamp: Timestamp) -> Vec<WriteSpec> {
+    let mut specs = vec![];
+    for family in REGISTRY.gather() {
+        let name = family.get_name();
+        if !name.starts_with("tsample_") && !name.starts_with("process_") {
+            continue;
+        }
+        for metric in family.get_metric() {
+            let mut spec = WriteSpec::new(timestamp, INTERNAL_MEASUREMENT);
+            for label in metric.get_label() {
+                spec = spec.add_tag(label.get_name(), Type::Text(label.get_value().to_string()));
+            }
+            spec = match family.get_field_type() {
+                MetricType::COUNTER => {
+                    spec.add_field(name, Type::Float(metric.get_counter().get_value()))
+                }
+                MetricType::GAUGE => {
+                    spec.add_field(name, Type::Float(metric.get_gauge().get_value()))
+                }
+                MetricType::HISTOGRAM => {
+                    let histogram = metric.get_histogram();
+                    spec.add_field(
+                        format!("{}_count", name),
+                        Type::UnsignedInteger(histogram.get_sample_count()),
+                    )
+                    .add_field(
+                        format!("{}_sum", name),
+                        Type::Float(histogram.get_sample_sum()),
+                    )
+                }
+                _ => continue,
+            };
+            specs.push(spec);
+        }
+    }
+    specs
+}
+
+/// Sends the internal metrics every scrape interval while `export_internal_metrics` is
+/// enabled, until the collector stops. Both settings follow the configuration.
+pub async fn export_internal_metrics(sender: Sender<Vec<WriteSpec>>, mut config: ConfigReceiver) {
+    let interval_of = |tc: &TestConfig| Duration::from_secs(tc.scrap_interval.max(1));
+    let mut interval = interval_of(&config.borrow_and_update());
+    let mut timer = tokio::time::interval(interval);
+    timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = timer.tick() => {}
+            Ok(_) = config.changed() => {
+                let new_interval = interval_of(&config.borrow_and_update());
+                if new_interval != interval {
+                    interval = new_interval;
+                    timer = tokio::time::interval(interval);
+                    timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+                }
+                continue;
+            }
+        }
+        if !config.borrow().export_internal_metrics {
+            continue;
+        }
+        let timestamp = Timestamp::Milliseconds(chrono::Utc::now().timestamp_millis() as u128);
+        if sender.send(internal_specs(timestamp)).await.is_err() {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scrape_and_internal_specs() {
+        init();
+        let mut scrape = Scrape::start("test_platform", "subsystem", "ok");
+        scrape.response(StatusCode::OK);
+        scrape.finish();
+        {
+            // dropped without a result, like an early return.
+            let _scrape = Scrape::start("test_platform", "subsystem", "failed");
+        }
+        let labels = |target| ["test_platform", "subsystem", target];
+        assert_eq!(SCRAPES.with_label_values(&labels("ok")).get(), 1);
+        assert_eq!(SCRAPE_FAILURES.with_label_values(&labels("ok")).get(), 0);
+        assert_eq!(
+            SCRAPE_FAILURES.with_label_values(&labels("failed")).get(),
+            1
+        );
+
+        let specs = internal_specs(Timestamp::Milliseconds(0));
+        let failures = specs
+            .iter()
+            .find(|spec| {
+                spec.fields[0].0 == "tsample_scrape_failures_total"
+                    && spec
+                        .tags
+                        .iter()
+                        .any(|(key, value)| key == "target" && value.to_string() == "failed")
+            })
+            .unwrap();
+        assert_eq!(failures.measurement, INTERNAL_MEASUREMENT);
+        assert_eq!(failures.fields[0].1.to_string(), "1");
+        assert!(specs.iter().any(|spec| spec.fields.len() == 2
+            && spec.fields[0].0 == "tsample_scrape_duration_seconds_count"));
+    }
+
+    #[tokio::test]
+    async fn test_status_point() {
+        let mut scrape = Scrape::start("test_platform", "cxserver", "auth");
+        scrape.response(StatusCode::UNAUTHORIZED);
+        let status = scrape.finish();
+        assert_eq!(status.measurement, STATUS_MEASUREMENT);
+        let fields: Vec<String> = status
+            .fields
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect();
+        assert_eq!(fields[..3], ["up=0", "http_status=401", "error=auth"]);
+
+        // nothing listens on port 1.
+        let error = reqwest::Client::new()
+            .get("http://127.0.0.1:1/")
+            .send()
+            .await
+            .unwrap_err();
+        let mut scrape = Scrape::start("test_platform", "jmx", "connect");
+        scrape.error(&error.into());
+        let status = scrape.finish();
+        assert_eq!(status.fields[2].1.to_string(), "connect");
+    }
+}
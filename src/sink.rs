@@ -0,0 +1,475 @@
+use std::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::selfmetrics::{
+    SINK_BATCHES, SINK_DROPPED, SINK_ERRORS, SINK_POINTS, SINK_QUEUE_DEPTH, SINK_WRITE_DURATION,
+};
+use crate::spec::WriteSpec;
+use async_trait::async_trait;
+use prometheus::{IntCounter, IntGauge};
+use tokio::{
+    sync::mpsc::{
+        channel,
+        error::{TryRecvError, TrySendError},
+        Receiver, Sender,
+    },
+    task::JoinHandle,
+};
+
+// batches waiting for one sink, the newest batches are dropped once it's full.
+pub const SINK_QUEUE_SIZE: usize = 1000;
+
+const MAX_RESTART_BACKOFF: Duration = Duration::from_secs(60);
+
+/// An exporter fed with every batch of points collected by tsample.
+///
+/// Each sink runs in its own task with its own queue, so a slow or failing sink
+/// doesn't hold back the others.
+#[async_trait]
+pub trait Sink: Send {
+    /// Writes or buffers one batch, returns the number of points written to the export,
+    /// 0 while they are buffered. An error is logged and counted, the sink keeps running.
+    async fn write(&mut self, write_specs: &[WriteSpec]) -> anyhow::Result<usize>;
+
+    /// Called every `tick_interval()`, for the time based flushes and the retries.
+    /// Returns the number of points written.
+    async fn tick(&mut self) -> anyhow::Result<usize> {
+        Ok(0)
+    }
+
+    fn tick_interval(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Called once all the batches have been received, before the sink is dropped.
+    /// Returns the number of points written.
+    async fn close(&mut self) -> anyhow::Result<usize> {
+        Ok(0)
+    }
+}
+
+/// A failed flush of the points buffered by a sink: `written` points were written before
+/// the error and `lost` points are dropped, the others are kept to be written later.
+#[derive(Debug)]
+pub struct FlushError {
+    pub written: usize,
+    pub lost: usize,
+    pub error: anyhow::Error,
+}
+
+impl FlushError {
+    pub fn new(lost: usize, error: anyhow::Error) -> Self {
+        FlushError {
+            written: 0,
+            lost,
+            error,
+        }
+    }
+}
+
+impl fmt::Display for FlushError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.lost > 0 {
+            write!(f, "{} point(s) lost, ", self.lost)?;
+        }
+        write!(f, "{:#}", self.error)
+    }
+}
+
+impl std::error::Error for FlushError {}
+
+pub type SinkFuture = Pin<Box<dyn Future<Output = anyhow::Result<Box<dyn Sink>>> + Send>>;
+
+/// Creates the sink, it's called again with a growing delay until it succeeds.
+pub type SinkFactory = Box<dyn Fn() -> SinkFuture + Send + Sync>;
+
+/// One enabled export of the configuration.
+pub struct SinkSpec {
+    pub name: String,
+    // the configuration of the sink, it's created again when this changes.
+    pub settings: String,
+    pub factory: SinkFactory,
+}
+
+/// The internal metrics of one sink.
+#[derive(Debug)]
+pub struct SinkStats {
+    // batches accepted by the sink, and points written to the export.
+    pub batches: IntCounter,
+    pub points: IntCounter,
+    // failed writes, ticks and creations.
+    pub errors: IntCounter,
+    // points not delivered because the queue was full, the sink stopped or lost them.
+    pub dropped: IntCounter,
+    // batches waiting in the queue.
+    pub queued: IntGauge,
+}
+
+impl SinkStats {
+    pub fn new(name: &str) -> Self {
+        SinkStats {
+            batches: SINK_BATCHES.with_label_values(&[name]),
+            points: SINK_POINTS.with_label_values(&[name]),
+            errors: SINK_ERRORS.with_label_values(&[name]),
+            dropped: SINK_DROPPED.with_label_values(&[name]),
+            queued: SINK_QUEUE_DEPTH.with_label_values(&[name]),
+        }
+    }
+}
+
+struct SinkHandle {
+    name: String,
+    settings: String,
+    sender: Sender<Arc<Vec<WriteSpec>>>,
+    stats: Arc<SinkStats>,
+    task: JoinHandle<()>,
+    last_drop_warning: Option<Instant>,
+}
+
+/// Fans out every batch to the sinks.
+#[derive(Default)]
+pub struct Dispatcher {
+    sinks: Vec<SinkHandle>,
+}
+
+impl Dispatcher {
+    pub fn new() -> Self {
+        Dispatcher::default()
+    }
+
+    pub fn add(&mut self, spec: SinkSpec) {
+        let SinkSpec {
+            name,
+            settings,
+            factory,
+        } = spec;
+        let (sender, receiver) = channel(SINK_QUEUE_SIZE);
+        let stats = Arc::new(SinkStats::new(&name));
+        let task = tokio::spawn(run_sink(name.clone(), factory, receiver, stats.clone()));
+        log::info!("{} sink launched.", name);
+        self.sinks.push(SinkHandle {
+            name,
+            settings,
+            sender,
+            stats,
+            task,
+            last_drop_warning: None,
+        });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sinks.is_empty()
+    }
+
+    /// Queues the batch for every sink, without waiting for a full queue.
+    pub fn dispatch(&mut self, write_specs: Vec<WriteSpec>) {
+        let write_specs = Arc::new(write_specs);
+        for sink in self.sinks.iter_mut() {
+            let error = match sink.sender.try_send(write_specs.clone()) {
+                Ok(_) => {
+                    sink.stats.queued.inc();
+                    continue;
+                }
+                Err(TrySendError::Full(_)) => "queue is full",
+                Err(TrySendError::Closed(_)) => "sink stopped",
+            };
+            sink.stats.dropped.inc_by(write_specs.len() as u64);
+            // at most one warning per minute and sink.
+            if sink
+                .last_drop_warning
+                .map_or(true, |last| last.elapsed() >= Duration::from_secs(60))
+            {
+                sink.last_drop_warning = Some(Instant::now());
+                log::warn!(
+                    "{} sink {}, points dropped so far:{}",
+                    sink.name,
+                    error,
+                    sink.stats.dropped.get()
+                );
+            }
+        }
+    }
+
+    /// Applies a new configuration: the removed and changed sinks write what they have and
+    /// stop, then the new and changed ones are launched. The unchanged sinks keep running.
+    pub async fn reconcile(&mut self, specs: Vec<SinkSpec>) {
+        let (kept, stopped): (Vec<_>, Vec<_>) = self.sinks.drain(..).partition(|sink| {
+            specs
+                .iter()
+                .any(|spec| spec.name == sink.name && spec.settings == sink.settings)
+        });
+        self.sinks = kept;
+        for sink in stopped {
+            close_sink(sink).await;
+        }
+        for spec in specs {
+            if !self.sinks.iter().any(|sink| sink.name == spec.name) {
+                self.add(spec);
+            }
+        }
+    }
+
+    /// Closes the queues and waits for the sinks to write what they have.
+    pub async fn close(self) {
+        for sink in self.sinks {
+            close_sink(sink).await;
+        }
+    }
+}
+
+async fn close_sink(sink: SinkHandle) {
+    drop(sink.sender);
+    if let Err(e) = sink.task.await {
+        log::error!("{} sink task error:{:?}", sink.name, e);
+    }
+    log::info!(
+        "{} sink finished, points:{}, errors:{}, dropped:{}",
+        sink.name,
+        sink.stats.points.get(),
+        sink.stats.errors.get(),
+        sink.stats.dropped.get()
+    );
+}
+
+/// Dispatches the batches until all the senders are dropped, and applies the sinks of
+/// every new configuration.
+pub async fn run_dispatcher(
+    mut dispatcher: Dispatcher,
+    mut receiver: Receiver<Vec<WriteSpec>>,
+    mut updates: Receiver<Vec<SinkSpec>>,
+) {
+    let mut updating = true;
+    loop {
+        tokio::select! {
+            write_specs = receiver.recv() => match write_specs {
+                Some(write_specs) => dispatcher.dispatch(write_specs),
+                None => break,
+            },
+            specs = updates.recv(), if updating => match specs {
+                Some(specs) => dispatcher.reconcile(specs).await,
+                None => updating = false,
+            },
+        }
+    }
+    dispatcher.close().await;
+}
+
+async fn run_sink(
+    name: String,
+    factory: SinkFactory,
+    mut receiver: Receiver<Arc<Vec<WriteSpec>>>,
+    stats: Arc<SinkStats>,
+) {
+    let mut backoff = Duration::from_secs(1);
+    let mut sink = loop {
+        match factory().await {
+            Ok(sink) => break sink,
+            Err(e) => {
+                stats.errors.inc();
+                log::error!(
+                    "failed to create {} sink, retry in {:?}:{:?}",
+                    name,
+                    backoff,
+                    e
+                );
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_RESTART_BACKOFF);
+                // the batches received meanwhile can't be written.
+                loop {
+                    match receiver.try_recv() {
+                        Ok(write_specs) => {
+                            stats.queued.dec();
+                            stats.dropped.inc_by(write_specs.len() as u64);
+                        }
+                        Err(TryRecvError::Empty) => break,
+                        Err(TryRecvError::Disconnected) => return,
+                    }
+                }
+            }
+        }
+    };
+
+    let write_duration = SINK_WRITE_DURATION.with_label_values(&[&name]);
+    let tick_interval = sink.tick_interval();
+    let mut tick_timer = tokio::time::interval(tick_interval.unwrap_or(Duration::from_secs(1)));
+    tick_timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        let write_specs = tokio::select! {
+            write_specs = receiver.recv() => write_specs,
+            _ = tick_timer.tick(), if tick_interval.is_some() => {
+                record(&name, &stats, sink.tick().await);
+                continue;
+            }
+        };
+        let write_specs = match write_specs {
+            Some(write_specs) => write_specs,
+            None => break,
+        };
+        stats.queued.dec();
+        let timer = write_duration.start_timer();
+        let result = sink.write(&write_specs).await;
+        timer.observe_duration();
+        if record(&name, &stats, result) {
+            stats.batches.inc();
+        }
+    }
+    record(&name, &stats, sink.close().await);
+}
+
+/// Counts the points written and lost by a write, tick or close, returns false on an error.
+fn record(name: &str, stats: &SinkStats, result: anyhow::Result<usize>) -> bool {
+    match result {
+        Ok(written) => {
+            stats.points.inc_by(written as u64);
+            true
+        }
+        Err(e) => {
+            stats.errors.inc();
+            if let Some(flush) = e.downcast_ref::<FlushError>() {
+                stats.points.inc_by(flush.written as u64);
+                stats.dropped.inc_by(flush.lost as u64);
+            }
+            log::error!("{} sink error:{:?}", name, e);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    struct CountingSink {
+        points: Arc<Mutex<usize>>,
+    }
+
+    #[async_trait]
+    impl Sink for CountingSink {
+        async fn write(&mut self, write_specs: &[WriteSpec]) -> anyhow::Result<usize> {
+            *self.points.lock().unwrap() += write_specs.len();
+            Ok(write_specs.len())
+        }
+    }
+
+    struct FailingSink;
+
+    #[async_trait]
+    impl Sink for FailingSink {
+        async fn write(&mut self, _write_specs: &[WriteSpec]) -> anyhow::Result<usize> {
+            Err(anyhow::anyhow!("failed"))
+        }
+    }
+
+    // buffers the points, and loses all but one of them when it's closed.
+    struct LosingSink {
+        buffered: usize,
+    }
+
+    #[async_trait]
+    impl Sink for LosingSink {
+        async fn write(&mut self, write_specs: &[WriteSpec]) -> anyhow::Result<usize> {
+            self.buffered += write_specs.len();
+            Ok(0)
+        }
+
+        async fn close(&mut self) -> anyhow::Result<usize> {
+            Err(FlushError {
+                written: 1,
+                lost: self.buffered - 1,
+                error: anyhow::anyhow!("failed"),
+            }
+            .into())
+        }
+    }
+
+    fn counting_spec(name: &str, settings: &str, points: Arc<Mutex<usize>>) -> SinkSpec {
+        SinkSpec {
+            name: name.to_string(),
+            settings: settings.to_string(),
+            factory: Box::new(move || {
+                let points = points.clone();
+                Box::pin(async move { Ok(Box::new(CountingSink { points }) as Box<dyn Sink>) })
+            }),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dispatcher_isolates_sinks() {
+        let points = Arc::new(Mutex::new(0));
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.add(counting_spec("counting", "", points.clone()));
+        dispatcher.add(SinkSpec {
+            name: "failing".to_string(),
+            settings: String::new(),
+            factory: Box::new(|| Box::pin(async { Ok(Box::new(FailingSink) as Box<dyn Sink>) })),
+        });
+        dispatcher.add(SinkSpec {
+            name: "losing".to_string(),
+            settings: String::new(),
+            factory: Box::new(|| {
+                Box::pin(async { Ok(Box::new(LosingSink { buffered: 0 }) as Box<dyn Sink>) })
+            }),
+        });
+        let stats: Vec<Arc<SinkStats>> = dispatcher.sinks.iter().map(|s| s.stats.clone()).collect();
+
+        let spec = WriteSpec::new(influxdb::Timestamp::Milliseconds(0), "m");
+        dispatcher.dispatch(vec![spec.clone(), spec.clone()]);
+        dispatcher.dispatch(vec![spec]);
+        dispatcher.close().await;
+
+        assert_eq!(*points.lock().unwrap(), 3);
+        assert_eq!(stats[0].points.get(), 3);
+        assert_eq!(stats[1].errors.get(), 2);
+        assert_eq!(stats[1].queued.get(), 0);
+        // the points are counted when they're written, not when they're buffered.
+        assert_eq!(stats[2].batches.get(), 2);
+        assert_eq!(stats[2].points.get(), 1);
+        assert_eq!(stats[2].dropped.get(), 2);
+        assert_eq!(stats[2].errors.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_dispatcher_reconcile() {
+        let (kept, changed, removed, added) = (
+            Arc::new(Mutex::new(0)),
+            Arc::new(Mutex::new(0)),
+            Arc::new(Mutex::new(0)),
+            Arc::new(Mutex::new(0)),
+        );
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.add(counting_spec("kept", "a", kept.clone()));
+        dispatcher.add(counting_spec("changed", "a", changed.clone()));
+        dispatcher.add(counting_spec("removed", "a", removed.clone()));
+        let spec = WriteSpec::new(influxdb::Timestamp::Milliseconds(0), "m");
+        dispatcher.dispatch(vec![spec.clone()]);
+
+        let kept_sender = dispatcher.sinks[0].sender.clone();
+        dispatcher
+            .reconcile(vec![
+                counting_spec("kept", "a", kept.clone()),
+                counting_spec("changed", "b", changed.clone()),
+                counting_spec("added", "a", added.clone()),
+            ])
+            .await;
+        let names: Vec<&str> = dispatcher.sinks.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, vec!["kept", "changed", "added"]);
+        assert!(dispatcher.sinks[0].sender.same_channel(&kept_sender));
+        drop(kept_sender);
+        // the stopped sinks wrote their batch before closing.
+        assert_eq!(*changed.lock().unwrap(), 1);
+        assert_eq!(*removed.lock().unwrap(), 1);
+
+        dispatcher.dispatch(vec![spec]);
+        dispatcher.close().await;
+        assert_eq!(*kept.lock().unwrap(), 2);
+        assert_eq!(*changed.lock().unwrap(), 2);
+        assert_eq!(*removed.lock().unwrap(), 1);
+        assert_eq!(*added.lock().unwrap(), 1);
+    }
+}
@@ -0,0 +1,322 @@
+use std::{
+    collections::VecDeque,
+    fs,
+    io::Write,
+    path::PathBuf,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::selfmetrics::SPOOL_POINTS;
+use crate::testconfig::SpoolConfig;
+use lazy_static::lazy_static;
+use prometheus::IntCounter;
+
+lazy_static! {
+    // points moved into the spool because the sink was not reachable.
+    pub static ref SPOOLED_POINTS: IntCounter = SPOOL_POINTS.with_label_values(&["spooled"]);
+    // points removed from the spool because of the size or age limits.
+    pub static ref DROPPED_POINTS: IntCounter = SPOOL_POINTS.with_label_values(&["dropped"]);
+    // points written to the sink from the spool.
+    pub static ref REPLAYED_POINTS: IntCounter = SPOOL_POINTS.with_label_values(&["replayed"]);
+}
+
+const SPOOL_EXTENSION: &str = "lp";
+
+#[derive(Debug)]
+struct SpoolFile {
+    path: PathBuf,
+    created_ms: u64,
+    points: usize,
+    bytes: u64,
+}
+
+/// A bounded on-disk queue of line protocol batches.
+/// Each batch is one file named `{created_ms}-{sequence}-{points}.lp`, so the order
+/// and the number of points can be recovered from the directory after a restart.
+#[derive(Debug)]
+pub struct Spool {
+    directory: PathBuf,
+    max_bytes: u64,
+    max_age: Duration,
+    files: VecDeque<SpoolFile>,
+    total_bytes: u64,
+    sequence: u64,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    backoff: Duration,
+    next_retry: Instant,
+}
+
+impl Spool {
+    pub fn open(config: &SpoolConfig) -> anyhow::Result<Self> {
+        let directory = PathBuf::from(&config.directory);
+        fs::create_dir_all(&directory)?;
+
+        let mut files = vec![];
+        for entry in fs::read_dir(&directory)? {
+            let path = entry?.path();
+            match path.extension().and_then(|e| e.to_str()) {
+                Some(SPOOL_EXTENSION) => {}
+                Some("tmp") => {
+                    // an incomplete write from a previous run.
+                    let _ = fs::remove_file(&path);
+                    continue;
+                }
+                _ => continue,
+            }
+            let parsed = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(parse_file_stem);
+            match parsed {
+                Some((created_ms, sequence, points)) => {
+                    let bytes = fs::metadata(&path)?.len();
+                    files.push((
+                        sequence,
+                        SpoolFile {
+                            path,
+                            created_ms,
+                            points,
+                            bytes,
+                        },
+                    ));
+                }
+                None => log::warn!("unknown file in spool directory:{:?}", path),
+            }
+        }
+        files.sort_by_key(|(sequence, file)| (file.created_ms, *sequence));
+        let sequence = files.iter().map(|(s, _)| *s + 1).max().unwrap_or(0);
+        let files: VecDeque<SpoolFile> = files.into_iter().map(|(_, f)| f).collect();
+        let total_bytes = files.iter().map(|f| f.bytes).sum();
+        if !files.is_empty() {
+            log::info!(
+                "spool:{:?} has {} pending batch(es) from a previous run",
+                directory,
+                files.len()
+            );
+        }
+
+        let initial_backoff = Duration::from_secs(config.retry_initial_backoff.max(1));
+        Ok(Spool {
+            directory,
+            max_bytes: config.max_size_mb.saturating_mul(1024 * 1024),
+            max_age: Duration::from_secs(config.max_age),
+            files,
+            total_bytes,
+            sequence,
+            initial_backoff,
+            max_backoff: Duration::from_secs(config.retry_max_backoff).max(initial_backoff),
+            backoff: initial_backoff,
+            next_retry: Instant::now(),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    /// Appends one batch at the end of the queue, the oldest batches are dropped
+    /// when the size limit is exceeded.
+    pub fn push(&mut self, body: &str, points: usize) -> anyhow::Result<()> {
+        let created_ms = now_millis();
+        let stem = format!("{:013}-{:010}-{}", created_ms, self.sequence, points);
+        self.sequence += 1;
+
+        let mut path = self.directory.join(&stem);
+        path.set_extension("tmp");
+        {
+            let mut file = fs::File::create(&path)?;
+            file.write_all(body.as_bytes())?;
+            file.sync_all()?;
+        }
+        let final_path = path.with_extension(SPOOL_EXTENSION);
+        fs::rename(&path, &final_path)?;
+
+        let bytes = body.len() as u64;
+        self.total_bytes += bytes;
+        self.files.push_back(SpoolFile {
+            path: final_path,
+            created_ms,
+            points,
+            bytes,
+        });
+        SPOOLED_POINTS.inc_by(points as u64);
+
+        while self.total_bytes > self.max_bytes && self.files.len() > 1 {
+            log::warn!("spool size limit exceeded, dropping the oldest batch");
+            self.drop_front();
+        }
+        Ok(())
+    }
+
+    /// Drops the batches older than the configured maximum age.
+    pub fn expire(&mut self) {
+        if self.max_age.is_zero() {
+            return;
+        }
+        let oldest_allowed = now_millis().saturating_sub(self.max_age.as_millis() as u64);
+        while let Some(file) = self.files.front() {
+            if file.created_ms >= oldest_allowed {
+                break;
+            }
+            log::warn!("spooled batch is too old, dropping:{:?}", file.path);
+            self.drop_front();
+        }
+    }
+
+    /// Reads the oldest batch and its number of points.
+    pub fn front(&mut self) -> Option<(String, usize)> {
+        loop {
+            let file = self.files.front()?;
+            match fs::read_to_string(&file.path) {
+                Ok(body) => return Some((body, file.points)),
+                Err(e) => {
+                    log::error!(
+                        "failed to read spooled batch:{:?}, error:{:?}",
+                        file.path,
+                        e
+                    );
+                    self.drop_front();
+                }
+            }
+        }
+    }
+
+    /// Removes the oldest batch after it has been written successfully.
+    pub fn pop_front(&mut self) {
+        if let Some(file) = self.remove_front() {
+            REPLAYED_POINTS.inc_by(file.points as u64);
+        }
+    }
+
+    pub fn is_ready(&self) -> bool {
+        Instant::now() >= self.next_retry
+    }
+
+    pub fn record_success(&mut self) {
+        self.backoff = self.initial_backoff;
+        self.next_retry = Instant::now();
+    }
+
+    pub fn record_failure(&mut self) {
+        self.next_retry = Instant::now() + self.backoff;
+        log::warn!(
+            "spool replay failed, next retry in {:?}, pending batch(es):{}",
+            self.backoff,
+            self.files.len()
+        );
+        self.backoff = (self.backoff * 2).min(self.max_backoff);
+    }
+
+    /// Removes the oldest batch without writing it.
+    pub fn drop_front(&mut self) {
+        if let Some(file) = self.remove_front() {
+            DROPPED_POINTS.inc_by(file.points as u64);
+        }
+    }
+
+    fn remove_front(&mut self) -> Option<SpoolFile> {
+        let file = self.files.pop_front()?;
+        self.total_bytes = self.total_bytes.saturating_sub(file.bytes);
+        if let Err(e) = fs::remove_file(&file.path) {
+            log::error!(
+                "failed to remove spooled batch:{:?}, error:{:?}",
+                file.path,
+                e
+            );
+        }
+        Some(file)
+    }
+}
+
+fn parse_file_stem(stem: &str) -> Option<(u64, u64, usize)> {
+    let mut parts = stem.split('-');
+    let created_ms = parts.next()?.parse().ok()?;
+    let sequence = parts.next()?.parse().ok()?;
+    let points = parts.next()?.parse().ok()?;
+    if parts.next().is_some() {
+        return None;
+    }
+    Some((created_ms, sequence, points))
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_config(name: &str) -> SpoolConfig {
+        let directory =
+            std::env::temp_dir().join(format!("tsample-spool-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        SpoolConfig {
+            directory: directory.to_string_lossy().to_string(),
+            ..SpoolConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_spool_order_and_reopen() {
+        let config = test_config("order");
+        let mut spool = Spool::open(&config).unwrap();
+        assert!(spool.is_empty());
+        spool.push("m v=1i 1\n", 1).unwrap();
+        spool.push("m v=2i 2\nm v=3i 3\n", 2).unwrap();
+        drop(spool);
+
+        let mut spool = Spool::open(&config).unwrap();
+        assert_eq!(spool.len(), 2);
+        assert_eq!(spool.front().unwrap(), ("m v=1i 1\n".to_string(), 1));
+        spool.pop_front();
+        assert_eq!(
+            spool.front().unwrap(),
+            ("m v=2i 2\nm v=3i 3\n".to_string(), 2)
+        );
+        spool.push("m v=4i 4\n", 1).unwrap();
+        spool.pop_front();
+        assert_eq!(spool.front().unwrap(), ("m v=4i 4\n".to_string(), 1));
+        spool.pop_front();
+        assert!(spool.is_empty());
+
+        fs::remove_dir_all(&config.directory).unwrap();
+    }
+
+    #[test]
+    fn test_spool_size_limit() {
+        let mut config = test_config("limit");
+        config.max_size_mb = 0;
+        let mut spool = Spool::open(&config).unwrap();
+        spool.push("m v=1i 1\n", 1).unwrap();
+        spool.push("m v=2i 2\n", 1).unwrap();
+        // the newest batch is always kept.
+        assert_eq!(spool.len(), 1);
+        assert_eq!(spool.front().unwrap().0, "m v=2i 2\n");
+
+        fs::remove_dir_all(&config.directory).unwrap();
+    }
+
+    #[test]
+    fn test_spool_backoff() {
+        let config = test_config("backoff");
+        let mut spool = Spool::open(&config).unwrap();
+        assert!(spool.is_ready());
+        spool.record_failure();
+        assert!(!spool.is_ready());
+        assert_eq!(spool.backoff, Duration::from_secs(2));
+        spool.record_success();
+        assert!(spool.is_ready());
+        assert_eq!(spool.backoff, Duration::from_secs(1));
+
+        fs::remove_dir_all(&config.directory).unwrap();
+    }
+}
@@ -0,0 +1,184 @@
+use crate::prometheus::gauge_value;
+use crate::sink::Sink;
+use crate::spec::WriteSpec;
+use crate::testconfig::ExportToStatsd;
+use async_trait::async_trait;
+use tokio::net::UdpSocket;
+
+// StatsD over UDP: "name:value|type", several lines per packet separated by '\n'.
+// With the DogStatsD extension the tags follow as "|#key:value,key:value".
+// https://github.com/statsd/statsd/blob/master/docs/metric_types.md
+// https://docs.datadoghq.com/developers/dogstatsd/datagram_shell
+
+pub struct StatsdSink {
+    socket: UdpSocket,
+    prefix: String,
+    dogstatsd: bool,
+    mtu: usize,
+}
+
+impl StatsdSink {
+    pub async fn new(config: &ExportToStatsd) -> anyhow::Result<Self> {
+        let address = format!("{}:{}", config.host, config.port);
+        log::info!(
+            "statsd service launched, address:{}, dogstatsd:{}",
+            address,
+            config.dogstatsd
+        );
+        let target = tokio::net::lookup_host(&address)
+            .await?
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("failed to resolve statsd address:{}", address))?;
+        let socket = if target.is_ipv4() {
+            UdpSocket::bind("0.0.0.0:0").await?
+        } else {
+            UdpSocket::bind("[::]:0").await?
+        };
+        socket.connect(target).await?;
+        Ok(StatsdSink {
+            socket,
+            prefix: config.prefix.clone(),
+            dogstatsd: config.dogstatsd,
+            mtu: config.mtu,
+        })
+    }
+}
+
+#[async_trait]
+impl Sink for StatsdSink {
+    async fn write(&mut self, write_specs: &[WriteSpec]) -> anyhow::Result<usize> {
+        let mut lines = vec![];
+        for spec in write_specs.iter() {
+            encode(spec, &self.prefix, self.dogstatsd, &mut lines);
+        }
+        for packet in packets(&lines, self.mtu) {
+            // UDP is fire and forget, an error here is usually a closed port on the agent side.
+            if let Err(e) = self.socket.send(packet.as_bytes()).await {
+                log::warn!("statsd send error:{:?}", e);
+            }
+        }
+        Ok(write_specs.len())
+    }
+}
+
+/// Appends one line per numeric field of the spec, gauges except `ResponseTime`,
+/// which is sent as a timer in milliseconds.
+pub fn encode(spec: &WriteSpec, prefix: &str, dogstatsd: bool, lines: &mut Vec<String>) {
+    let mut tags = String::new();
+    if dogstatsd {
+        for (key, value) in spec.tags.iter() {
+            let value = value.to_string();
+            if value.is_empty() {
+                continue;
+            }
+            tags.push(if tags.is_empty() { '#' } else { ',' });
+            tags.push_str(&sanitize(key, &[':', ',', '|', '#']));
+            tags.push(':');
+            tags.push_str(&sanitize(&value, &[',', '|', '#']));
+        }
+    }
+
+    for (field, value) in spec.fields.iter() {
+        let value = match gauge_value(value) {
+            Some(value) if value.is_finite() => value,
+            _ => continue,
+        };
+        let mut name = String::new();
+        if !prefix.is_empty() {
+            name.push_str(prefix);
+            name.push('.');
+        }
+        name.push_str(&sanitize(&spec.measurement, &[':', '|', '@', '#', '.']));
+        name.push('.');
+        name.push_str(&sanitize(field, &[':', '|', '@', '#', '.']));
+
+        let mut push = |value: f64, metric_type: &str| {
+            let mut line = format!("{}:{}|{}", name, value, metric_type);
+            if !tags.is_empty() {
+                line.push('|');
+                line.push_str(&tags);
+            }
+            lines.push(line);
+        };
+        if field == "ResponseTime" {
+            push(value / 1000000.0, "ms"); // convert to milliseconds from nanoseconds
+        } else {
+            // a signed gauge value is a change of the current value, a negative value
+            // can only be set from zero.
+            if value < 0.0 {
+                push(0.0, "g");
+            }
+            push(value, "g");
+        }
+    }
+}
+
+/// Joins the lines into packets of at most `mtu` bytes, a longer line is sent alone.
+pub fn packets(lines: &[String], mtu: usize) -> Vec<String> {
+    let mut packets = vec![];
+    let mut packet = String::new();
+    for line in lines {
+        if !packet.is_empty() && packet.len() + 1 + line.len() > mtu {
+            packets.push(std::mem::take(&mut packet));
+        }
+        if !packet.is_empty() {
+            packet.push('\n');
+        }
+        packet.push_str(line);
+    }
+    if !packet.is_empty() {
+        packets.push(packet);
+    }
+    packets
+}
+
+fn sanitize(value: &str, special: &[char]) -> String {
+    value
+        .chars()
+        .map(|c| {
+            if special.contains(&c) || c.is_whitespace() {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use influxdb::{Timestamp, Type};
+
+    #[test]
+    fn test_encode() {
+        let spec = WriteSpec::new(Timestamp::Milliseconds(0), "ConnectionServer")
+            .add_tag("Platform", Type::Text("p1".to_string()))
+            .add_tag("Provider", Type::Text("".to_string()))
+            .add_tag("cxserver", Type::Text("cx:1".to_string()))
+            .add_field("queue size", Type::SignedInteger(-2))
+            .add_field("ResponseTime", Type::SignedInteger(1500000));
+
+        let mut lines = vec![];
+        encode(&spec, "twx", true, &mut lines);
+        assert_eq!(
+            lines,
+            vec![
+                "twx.ConnectionServer.queue_size:0|g|#Platform:p1,cxserver:cx:1",
+                "twx.ConnectionServer.queue_size:-2|g|#Platform:p1,cxserver:cx:1",
+                "twx.ConnectionServer.ResponseTime:1.5|ms|#Platform:p1,cxserver:cx:1",
+            ]
+        );
+
+        let mut lines = vec![];
+        encode(&spec, "", false, &mut lines);
+        assert_eq!(lines[2], "ConnectionServer.ResponseTime:1.5|ms");
+    }
+
+    #[test]
+    fn test_packets() {
+        let lines: Vec<String> = vec!["a:1|g".into(), "b:2|g".into(), "c:3|g".into()];
+        assert_eq!(packets(&lines, 11), vec!["a:1|g\nb:2|g", "c:3|g"]);
+        assert_eq!(packets(&lines, 3), vec!["a:1|g", "b:2|g", "c:3|g"]);
+    }
+}
@@ -1,6 +1,8 @@
+use crate::check::Severity;
+use crate::include;
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
-use std::{fs::File, io::Read};
+use std::collections::BTreeMap;
 
 // use url::Url;
 
@@ -33,11 +35,36 @@ pub struct ExportToPrometheus {
     // pub counter_list: Vec<String>,
     #[serde(default = "default_response_time_bucket_bin")]
     pub response_time_bucket_bin: Vec<f64>,
+    // the listen address, like "0.0.0.0", "::" (IPv6) or "127.0.0.1" (localhost only).
+    #[serde(default = "default_bind_address")]
+    pub bind_address: String,
+    // both are needed to serve the metrics over HTTPS.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls_cert: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls_key: Option<String>,
+    // basic authentication, both username and password are needed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    // "Authorization: Bearer xxx" authentication, it can be used together with the basic authentication.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bearer_token: Option<String>,
+    // a series not updated for this many scrape intervals is removed, 0 keeps it forever.
+    #[serde(default = "default_stale_series_cycles")]
+    pub stale_series_cycles: u64,
 }
 
 fn default_prometheus_port() -> u16 {
     19090
 }
+fn default_stale_series_cycles() -> u64 {
+    3
+}
+fn default_bind_address() -> String {
+    "0.0.0.0".to_string()
+}
 fn default_endpoint() -> String {
     "metrics".to_string()
 }
@@ -48,11 +75,176 @@ fn default_response_time_bucket_bin() -> Vec<f64> {
 //     vec![]
 // }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExportToRemoteWrite {
+    pub enabled: bool,
+    // the receiver url, like "http://prometheus:9090/api/v1/write".
+    pub url: String,
+    // samples are sent in batches, a batch is sent once it has batch_size samples
+    // or flush_interval (milliseconds) has passed since the last write.
+    #[serde(default = "default_batch_size")]
+    pub batch_size: usize,
+    #[serde(default = "default_flush_interval")]
+    pub flush_interval: u64,
+    // a failed batch is sent again up to max_retries times, the delay (milliseconds) doubles each time.
+    #[serde(default = "default_remote_write_max_retries")]
+    pub max_retries: u32,
+    #[serde(default = "default_remote_write_retry_backoff")]
+    pub retry_backoff: u64,
+    // request timeout in seconds.
+    #[serde(default = "default_remote_write_timeout")]
+    pub timeout: u64,
+    // basic authentication, both username and password are needed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bearer_token: Option<String>,
+    // additional request headers, like "X-Scope-OrgID" for Mimir.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+}
+
+fn default_remote_write_max_retries() -> u32 {
+    3
+}
+fn default_remote_write_retry_backoff() -> u64 {
+    500
+}
+fn default_remote_write_timeout() -> u64 {
+    30
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExportToOtlp {
+    pub enabled: bool,
+    // the OTLP/HTTP metrics endpoint, like "http://collector:4318/v1/metrics".
+    pub endpoint: String,
+    // the "service.name" resource attribute.
+    #[serde(default = "default_otlp_service_name")]
+    pub service_name: String,
+    // points are sent in batches, a batch is sent once it has batch_size points
+    // or flush_interval (milliseconds) has passed since the last export.
+    #[serde(default = "default_batch_size")]
+    pub batch_size: usize,
+    #[serde(default = "default_flush_interval")]
+    pub flush_interval: u64,
+    // a failed batch is sent again up to max_retries times, the delay (milliseconds) doubles each time.
+    #[serde(default = "default_remote_write_max_retries")]
+    pub max_retries: u32,
+    #[serde(default = "default_remote_write_retry_backoff")]
+    pub retry_backoff: u64,
+    // request timeout in seconds.
+    #[serde(default = "default_remote_write_timeout")]
+    pub timeout: u64,
+    // additional request headers, like an "Authorization" header.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+    // bucket bounds (ms) of the ResponseTime histogram.
+    #[serde(default = "default_response_time_bucket_bin")]
+    pub response_time_bucket_bin: Vec<f64>,
+}
+
+fn default_otlp_service_name() -> String {
+    String::from("tsample")
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExportToGraphite {
+    pub enabled: bool,
+    pub host: String,
+    #[serde(default = "default_graphite_port")]
+    pub port: u16,
+    // the metric path, {measurement} and {field} are replaced by their values, any other {name} by the tag value.
+    #[serde(default = "default_graphite_template")]
+    pub template: String,
+    // append the tags to the path with the Graphite 1.1 tagged series syntax (path;tag=value).
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub tagged: bool,
+    // connect and write timeout in seconds.
+    #[serde(default = "default_graphite_timeout")]
+    pub timeout: u64,
+    // in seconds, the delay before reconnecting doubles after each failure up to this value.
+    #[serde(default = "default_graphite_max_reconnect_backoff")]
+    pub max_reconnect_backoff: u64,
+    // the lines not sent yet are kept up to this size while graphite is not reachable.
+    #[serde(default = "default_graphite_max_pending_mb")]
+    pub max_pending_mb: u64,
+}
+
+fn default_graphite_port() -> u16 {
+    2003
+}
+fn default_graphite_template() -> String {
+    String::from("twx.{Platform}.{measurement}.{Provider}.{field}")
+}
+fn default_graphite_timeout() -> u64 {
+    10
+}
+fn default_graphite_max_reconnect_backoff() -> u64 {
+    60
+}
+fn default_graphite_max_pending_mb() -> u64 {
+    16
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExportToStatsd {
+    pub enabled: bool,
+    #[serde(default = "default_statsd_host")]
+    pub host: String,
+    #[serde(default = "default_statsd_port")]
+    pub port: u16,
+    // metric names are "{prefix}.{measurement}.{field}".
+    #[serde(default = "default_statsd_prefix")]
+    pub prefix: String,
+    // send the tags with the DogStatsD syntax (|#key:value), plain StatsD has no tags.
+    #[serde(default = "default_statsd_dogstatsd")]
+    pub dogstatsd: bool,
+    // maximum packet size in bytes, the lines are batched up to this size.
+    #[serde(default = "default_statsd_mtu")]
+    pub mtu: usize,
+}
+
+fn default_statsd_host() -> String {
+    String::from("127.0.0.1")
+}
+fn default_statsd_port() -> u16 {
+    8125
+}
+fn default_statsd_prefix() -> String {
+    String::from("twx")
+}
+fn default_statsd_dogstatsd() -> bool {
+    true
+}
+fn default_statsd_mtu() -> usize {
+    1432
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ExportToFile {
     pub directory: String,
     pub auto_create_folder: bool,
     pub enabled: bool,
+    #[serde(default)]
+    pub format: FileFormat,
+    // a new file is started every day or every hour (UTC).
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub rotation: FileRotation,
+    // a new file is also started once the current one reaches this size.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_file_size_mb: Option<u64>,
+    // gzip the files once they are closed.
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub compress: bool,
+    // keep at most this number of files, the oldest ones will be deleted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retention_count: Option<usize>,
+    // in seconds, files older than this will be deleted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retention_age: Option<u64>,
 }
 impl Default for ExportToFile {
     fn default() -> Self {
@@ -60,9 +252,43 @@ impl Default for ExportToFile {
             directory: String::from("./export"),
             auto_create_folder: true,
             enabled: false,
+            format: FileFormat::default(),
+            rotation: FileRotation::default(),
+            max_file_size_mb: None,
+            compress: false,
+            retention_count: None,
+            retention_age: None,
         }
     }
 }
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum FileFormat {
+    #[default]
+    LineProtocol,
+    JsonLines,
+    Csv,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum FileRotation {
+    #[default]
+    Daily,
+    Hourly,
+}
+
+impl FileFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            FileFormat::LineProtocol => "lp",
+            FileFormat::JsonLines => "jsonl",
+            FileFormat::Csv => "csv",
+        }
+    }
+}
+#[allow(dead_code)]
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ThingworxMetric {
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -85,16 +311,130 @@ pub struct ExportToInfluxDB {
         skip_serializing_if = "is_default_protocol"
     )]
     pub protocol: String,
+    // 1 for InfluxDB 1.x (/write), 2 for InfluxDB 2.x and 3.x (/api/v2/write).
+    #[serde(
+        default = "default_influx_api_version",
+        skip_serializing_if = "is_default_influx_api_version"
+    )]
+    pub api_version: u8,
+    #[serde(default = "default_database")]
     pub database: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub username: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub password: Option<String>,
+    // the following settings are only used by api_version 2.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub org: Option<String>,
+    // bucket name, the database name will be used if it's not configured.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bucket: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    // one of "ns", "us", "ms" and "s".
+    #[serde(default = "default_precision")]
+    pub precision: String,
+    // points are written in batches, a batch is sent once it has batch_size points
+    // or flush_interval (milliseconds) has passed since the last write.
+    #[serde(default = "default_batch_size")]
+    pub batch_size: usize,
+    #[serde(default = "default_flush_interval")]
+    pub flush_interval: u64,
+    // batches that failed to be written will be kept on disk and replayed later.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub spool: Option<SpoolConfig>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SpoolConfig {
+    #[serde(default = "default_spool_directory")]
+    pub directory: String,
+    // the oldest batches will be dropped once the spool is larger than this.
+    #[serde(default = "default_spool_max_size_mb")]
+    pub max_size_mb: u64,
+    // in seconds, older batches will be dropped. 0 means no limit.
+    #[serde(default = "default_spool_max_age")]
+    pub max_age: u64,
+    // in seconds, the delay before the replay is retried doubles after each failure.
+    #[serde(default = "default_spool_retry_initial_backoff")]
+    pub retry_initial_backoff: u64,
+    #[serde(default = "default_spool_retry_max_backoff")]
+    pub retry_max_backoff: u64,
+}
+
+fn default_spool_directory() -> String {
+    String::from("./spool")
+}
+
+fn default_spool_max_size_mb() -> u64 {
+    512
 }
+
+fn default_spool_max_age() -> u64 {
+    3 * 24 * 3600
+}
+
+fn default_spool_retry_initial_backoff() -> u64 {
+    1
+}
+
+fn default_spool_retry_max_backoff() -> u64 {
+    300
+}
+
+impl Default for SpoolConfig {
+    fn default() -> Self {
+        SpoolConfig {
+            directory: default_spool_directory(),
+            max_size_mb: default_spool_max_size_mb(),
+            max_age: default_spool_max_age(),
+            retry_initial_backoff: default_spool_retry_initial_backoff(),
+            retry_max_backoff: default_spool_retry_max_backoff(),
+        }
+    }
+}
+
+impl ExportToInfluxDB {
+    pub fn is_v2(&self) -> bool {
+        self.api_version >= 2
+    }
+
+    pub fn get_bucket(&self) -> &str {
+        match self.bucket {
+            Some(ref bucket) => bucket,
+            None => &self.database,
+        }
+    }
+}
+
 fn default_thingworx_port() -> u16 {
     8080
 }
 
+fn default_influx_api_version() -> u8 {
+    1
+}
+
+fn is_default_influx_api_version(v: &u8) -> bool {
+    *v == 1
+}
+
+fn default_database() -> String {
+    String::from("thingworx")
+}
+
+fn default_precision() -> String {
+    String::from("ms")
+}
+
+fn default_batch_size() -> usize {
+    5000
+}
+
+fn default_flush_interval() -> u64 {
+    1000
+}
+
 fn is_default_protocol(s: &str) -> bool {
     s == "http"
 }
@@ -105,9 +445,17 @@ impl Default for ExportToInfluxDB {
             server_name: String::from("localhost"),
             port: 8086,
             protocol: String::from("http"),
+            api_version: 1,
             database: String::from("thingworx"),
             username: None,
             password: None,
+            org: None,
+            bucket: None,
+            token: None,
+            precision: default_precision(),
+            batch_size: default_batch_size(),
+            flush_interval: default_flush_interval(),
+            spool: None,
         }
     }
 }
@@ -118,26 +466,42 @@ impl Default for ExportToInfluxDB {
 //     pub metrics: Vec<String>,
 // }
 
-#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
 pub struct JmxMetric {
     pub name: String,
     pub object_name_pattern: String,
     pub name_label_alternative: Option<String>,
     #[serde(default = "default_metrics")]
     pub metrics: Vec<String>,
+    #[serde(flatten)]
+    pub schedule: Schedule,
 }
 
 fn default_metrics() -> Vec<String> {
     vec![]
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
 pub struct ConnectionServers {
     pub names: Vec<String>,
     pub metrics: Vec<String>,
+    // the schedule of every connection server of this server.
+    #[serde(flatten)]
+    pub schedule: Schedule,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// Overrides the schedule of a source, each source is queried by its own timer.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Schedule {
+    // seconds between two queries, default is the scrap_interval of the server.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interval: Option<u64>,
+    // seconds the first query is delayed after the start, to spread the queries over the interval.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offset: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct SubSystem {
     pub name: String,
     #[serde(default = "default_subsystem_enabled")]
@@ -148,6 +512,8 @@ pub struct SubSystem {
     pub split_desc_asprefix: bool,
     #[serde(default, skip_serializing_if = "is_default")]
     pub sanitize: bool,
+    #[serde(flatten)]
+    pub schedule: Schedule,
 }
 
 fn is_default<T: Default + PartialEq>(t: &T) -> bool {
@@ -169,7 +535,7 @@ fn default_subsystem_enabled() -> bool {
 //         }
 //     }
 // }
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct ArbitraryMetric {
     pub name: String,
     pub url: String,
@@ -181,9 +547,11 @@ pub struct ArbitraryMetric {
     pub split_desc_asprefix: bool,
     #[serde(default, skip_serializing_if = "is_default")]
     pub sanitize: bool,
+    #[serde(flatten)]
+    pub schedule: Schedule,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct ThingworxServer {
     pub name: String,
     pub host: String,
@@ -195,7 +563,15 @@ pub struct ThingworxServer {
     pub protocol: String,
     #[serde(default = "default_application")]
     pub application: String,
+    // sent as the appKey header, or as the Authorization header when it contains a space,
+    // like "Bearer xxxxx". not needed with an auth block.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub app_key: String,
+    // OAuth2 client credentials, the token is requested and refreshed by tsample.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auth: Option<ServerAuth>,
+    // default_subsystems when it's missing, an empty list queries no subsystem.
+    #[serde(default)]
     pub subsystems: Vec<SubSystem>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub connection_servers: Option<ConnectionServers>,
@@ -204,41 +580,169 @@ pub struct ThingworxServer {
     pub jmx_metrics: Option<Vec<JmxMetric>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub arbitrary_metrics: Option<Vec<ArbitraryMetric>>,
+    // overrides scrap_interval and scrap_jitter for the sources of this server.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scrap_interval: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scrap_jitter: Option<u64>,
+    // limits the queries in flight and the queries started per second to this server,
+    // across all the subsystems, connection servers, JMX and arbitrary metrics. no limit by default.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_concurrent_requests: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_requests_per_second: Option<f64>,
+    // a query failed with a timeout, a connection error or a 429, 502, 503 or 504 status is
+    // sent again up to max_retries times, the delay (milliseconds) doubles each time.
+    #[serde(default = "default_query_max_retries")]
+    pub max_retries: u32,
+    #[serde(default = "default_query_retry_backoff")]
+    pub retry_backoff: u64,
+    // stops querying the server after consecutive failures, disabled without this block.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+    // negotiate HTTP/2 with the server over TLS, HTTP/1.1 is used otherwise.
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub http2: bool,
+    // ask for gzip compressed responses.
+    #[serde(default = "default_gzip")]
+    pub gzip: bool,
+    // seconds an idle connection is kept open for the next cycle.
+    #[serde(default = "default_pool_idle_timeout")]
+    pub pool_idle_timeout: u64,
+    // without this block the server certificate is not verified.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls: Option<ServerTls>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ServerTls {
+    // verify the server certificate and its name, default is true.
+    #[serde(default = "default_verify")]
+    pub verify: bool,
+    // PEM file with the CA certificates trusted in addition to the built-in ones.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ca_file: Option<String>,
+    // PEM files of the client certificate and its private key, for mutual TLS.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_cert: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_key: Option<String>,
+    // the name sent with SNI, used in the Host header and verified in the certificate.
+    // the connection still goes to host.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server_name: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ServerAuth {
+    pub token_url: String,
+    pub client_id: String,
+    pub client_secret: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    // seconds before its expiry when the token is requested again.
+    #[serde(default = "default_refresh_before")]
+    pub refresh_before: u64,
+}
+
+fn default_refresh_before() -> u64 {
+    60
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CircuitBreakerConfig {
+    // consecutive failed queries that open the circuit, the queries are skipped while it's open.
+    #[serde(default = "default_failure_threshold")]
+    pub failure_threshold: u32,
+    // seconds before one query is let through to probe the server, the circuit closes when it succeeds.
+    #[serde(default = "default_probe_interval")]
+    pub probe_interval: u64,
+}
+
+fn default_failure_threshold() -> u32 {
+    5
+}
+
+fn default_probe_interval() -> u64 {
+    30
+}
+
+fn default_query_max_retries() -> u32 {
+    2
+}
+
+fn default_query_retry_backoff() -> u64 {
+    500
+}
+
+fn default_verify() -> bool {
+    true
+}
+
+fn default_gzip() -> bool {
+    true
+}
+
+fn default_pool_idle_timeout() -> u64 {
+    90
 }
 
 impl ThingworxServer {
+    /// The host name used in the URLs, the TLS server name when it's overridden.
+    pub fn url_host(&self) -> &str {
+        match self.tls.as_ref().and_then(|tls| tls.server_name.as_deref()) {
+            Some(server_name) => server_name,
+            None => &self.host,
+        }
+    }
+
     pub fn get_arbitrary_access_url(&self, url: &str) -> String {
         let splitter = if url.starts_with('/') { "" } else { "/" };
         format!(
             "{}://{}:{}/{}{}{}",
-            self.protocol, self.host, self.port, self.application, splitter, url
+            self.protocol,
+            self.url_host(),
+            self.port,
+            self.application,
+            splitter,
+            url
         )
     }
     pub fn get_cxserver_query_url(&self) -> String {
         format!(
             "{}://{}:{}/{}/ThingTemplates/ConnectionServer/Services/QueryImplementingThingsWithData",
-            self.protocol, self.host, self.port, self.application
+            self.protocol, self.url_host(), self.port, self.application
         )
     }
 
     pub fn get_query_mbeanstree_url(&self) -> String {
         format!(
             "{}://{}:{}/{}/Things/JMX.LocalServer/Services/QueryMBeansTree",
-            self.protocol, self.host, self.port, self.application
+            self.protocol,
+            self.url_host(),
+            self.port,
+            self.application
         )
     }
 
     pub fn get_mbean_attributeinfo_url(&self) -> String {
         format!(
             "{}://{}:{}/{}/Things/JMX.LocalServer/Services/GetMBeanAttributesInfo",
-            self.protocol, self.host, self.port, self.application
+            self.protocol,
+            self.url_host(),
+            self.port,
+            self.application
         )
     }
 
     pub fn get_cxserver_query_service_url(&self, cxserver_name: &str) -> String {
         format!(
             "{}://{}:{}/{}/Things/{}/Services/GetPerformanceMetrics",
-            self.protocol, self.host, self.port, self.application, cxserver_name
+            self.protocol,
+            self.url_host(),
+            self.port,
+            self.application,
+            cxserver_name
         )
     }
 }
@@ -256,12 +760,35 @@ pub struct TestConfig {
     pub query_time_out: u64,
     #[serde(default = "default_scrap_interval")]
     pub scrap_interval: u64,
+    // each query is delayed by a random time up to this many seconds, so the sources
+    // don't query ThingWorx at the same instant. default is 0.
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub scrap_jitter: u64,
     #[serde(default = "default_refresh_server_interval")]
     pub refresh_server_interval: u64,
+    // seconds between two checks of the configuration file, it's also reloaded on SIGHUP.
+    // 0 only reloads on SIGHUP.
+    #[serde(default = "default_config_reload_interval")]
+    pub config_reload_interval: u64,
+    // the subsystems of the servers without their own list.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub default_subsystems: Vec<SubSystem>,
     pub thingworx_servers: Vec<ThingworxServer>,
     pub export_to_influxdb: ExportToInfluxDB,
     pub export_to_file: Option<ExportToFile>,
     pub export_to_prometheus: Option<ExportToPrometheus>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub export_to_remote_write: Option<ExportToRemoteWrite>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub export_to_otlp: Option<ExportToOtlp>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub export_to_graphite: Option<ExportToGraphite>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub export_to_statsd: Option<ExportToStatsd>,
+    // send the internal metrics of tsample to the exports every scrap interval,
+    // as the tsample_internal measurement.
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub export_internal_metrics: bool,
 }
 
 fn default_query_time_out() -> u64 {
@@ -275,14 +802,57 @@ fn default_scrap_interval() -> u64 {
 fn default_refresh_server_interval() -> u64 {
     300
 }
+
+fn default_config_reload_interval() -> u64 {
+    10
+}
 impl TestConfig {
+    /// Loads the configuration, the problems found by `validate` are logged as warnings,
+    /// so a configuration that ran with an older version still starts.
     pub fn load_from_file(file_name: &str) -> Result<Self> {
-        let mut file = File::open(file_name)?;
-        let mut contents = String::new();
-        file.read_to_string(&mut contents)?;
-        let config: TestConfig = serde_yaml::from_str(&contents)?;
+        let config = Self::load(file_name)?;
+        let problems = config.validate();
+        for problem in problems.iter() {
+            log::warn!(
+                "configuration {}, {:?} at {}: {}",
+                file_name,
+                problem.severity,
+                problem.path,
+                problem.message
+            );
+        }
+        if !problems.is_empty() {
+            log::warn!(
+                "run \"tsample check -c {}\" to see the lines of the problems.",
+                file_name
+            );
+        }
         Ok(config)
     }
+
+    /// Loads the configuration and rejects it when `validate` finds an error, for a reload
+    /// where the running configuration can be kept.
+    pub fn load_valid(file_name: &str) -> Result<Self> {
+        let config = Self::load(file_name)?;
+        if let Some(problem) = config
+            .validate()
+            .into_iter()
+            .find(|problem| problem.severity == Severity::Error)
+        {
+            return Err(anyhow::anyhow!(
+                "invalid configuration, {}: {}. run \"tsample check -c {}\" to see all the problems.",
+                problem.path,
+                problem.message,
+                file_name
+            ));
+        }
+        Ok(config)
+    }
+
+    fn load(file_name: &str) -> Result<Self> {
+        let document = include::load(file_name)?;
+        Ok(serde_yaml::from_value(document)?)
+    }
 }
 // impl ThingworxMetric {
 //     pub fn get_url(&self) -> String {
@@ -1,194 +1,60 @@
 use std::{
-    collections::{BTreeMap, HashMap},
+    collections::{BTreeMap, HashMap, HashSet},
     time::SystemTime, sync::{atomic::{AtomicBool, Ordering}, Arc},
 };
 
 use crate::{
-    testconfig::{SubSystem, TestConfig, ThingworxServer, ArbitraryMetric},
+    testconfig::{SubSystem, ThingworxServer, ArbitraryMetric, Schedule},
     payload::{TwxJson, ConnectionServerResults}, jmxquery::{ refresh_jmx},
+    auth::{headers_for, invalidate},
+    breaker::run_query,
+    httpclient::{acquire, client_for},
+    reload::{sleep_or_reload, ConfigReceiver},
+    scheduler::Scheduler,
+    selfmetrics::{Scrape, COLLECTOR_QUEUE_DEPTH},
 };
 use chrono::offset::Utc;
 use chrono::DateTime;
 use influxdb::{/*WriteQuery,*/ Timestamp};
 use reqwest::{
     header::{HeaderMap, HeaderValue, CONTENT_TYPE, AUTHORIZATION},
-    Client,
+    Client, StatusCode,
 };
 use serde_json::Value as JsonValue;
 use tokio::sync::mpsc::Sender;
 use crate::spec::WriteSpec as WriteQuery;
 
 pub async fn launch_twxquery_service(
-    tc: TestConfig,
+    mut config: ConfigReceiver,
     sender: Sender<Vec<WriteQuery>>,
     running: Arc<AtomicBool>,
-    sleeping: Arc<AtomicBool>,
-    cxs_refresh_sleeping:  Arc<AtomicBool>,
-    jmx_refresh_sleeping:  Arc<AtomicBool>,
 ) -> anyhow::Result<()> {
     // launch a new service to query all the connection servers under each Thingworx server if it is configured.
     // this map will hold (k,v) where k is the thingworx server name and v is the list of connection servers name under this thingworx server.
-    let (cxserver_reader,mut cxserver_writer) = evmap::new();
-    let mut need_refresh_connection_server = false;
-    for server in tc.thingworx_servers.iter(){
-        if let Some(ref cxserver_config) = server.connection_servers{
-            if cxserver_config.names.is_empty(){
-                log::info!("no connection server name defined under thingworx server:{:?}", server.name);
-                need_refresh_connection_server = true;
-            }
-            cxserver_writer.insert(server.name.clone(), (cxserver_config.names.clone(),cxserver_config.metrics.clone()));
-        }
-    }
-    cxserver_writer.refresh();
-    if need_refresh_connection_server {
-        log::info!("need to refresh connection server name");
-        let tc_cxserver= tc.clone();
-        tokio::spawn(async move {
-            let _= refresh_connection_server(tc_cxserver,  cxserver_writer,cxs_refresh_sleeping).await;
-        });
-    }
+    // both refreshes follow the configuration, they have nothing to query while no server needs them.
+    let (cxserver_reader,cxserver_writer) = evmap::new();
+    let cxserver_task = tokio::spawn(refresh_connection_server(config.clone(), cxserver_writer));
 
-    let (jmx_reader,mut jmx_writer) = evmap::new();
-    let mut need_refresh_jmx = false;
-    for server in tc.thingworx_servers.iter() {
-        if let Some(ref jmx_config) = server.jmx_metrics {
-            if !jmx_config.is_empty() {
-                log::info!("JMX has been configured, JMX objectName needs to be refreshed from thingworx server:{:?}", server.name);
-                need_refresh_jmx = true;
-            }
-            
-        }
-    }
-    jmx_writer.refresh();
-    if need_refresh_jmx {
-        log::info!("need to refresh JMX object name");
-        let tc_jmx = tc.clone();
-        tokio::spawn(async move {
-            let _= refresh_jmx(tc_jmx,  jmx_writer,jmx_refresh_sleeping).await;
-        });
-    }
+    let (jmx_reader,jmx_writer) = evmap::new();
+    let jmx_task = tokio::spawn(refresh_jmx(config.clone(), jmx_writer));
 
-    let scrap_interval = tc.scrap_interval;
-    let query_timeout = tc.query_time_out; //default should be 20 seconds
-    log::info!("scrap interval is {} seconds, query timeout is:{} seconds.", scrap_interval, query_timeout);
+    {
+        let tc = config.borrow();
+        log::info!("scrap interval is {} seconds, query timeout is:{} seconds.", tc.scrap_interval, tc.query_time_out);
+    }
+    // every source has its own timer, the main loop only follows the configuration and
+    // the connection servers and the JMX groups found by the refreshes.
+    let mut scheduler = Scheduler::new(sender.clone(), cxserver_reader, jmx_reader);
     while running.load(Ordering::SeqCst) {
-        let start_time = SystemTime::now();
-        let mut tasks = vec![];
-        for server in tc.thingworx_servers.iter() {
-            let test_server = server.clone();
-            let test_sender = sender.clone();
-            // regular thingworx subsystem query
-            let task = tokio::spawn(async move {
-                match repeated_twxserver_query(&test_server, test_sender,query_timeout).await {
-                    Ok(_) => {
-                        // log::info!("twxquery service finished smoothly");
-                    }
-                    Err(e) => {
-                        log::error!("twxquery service error:{:?}", e);
-                    }
-                }
-            });
-
-            tasks.push(task);
-
-            // connection server query
-            let local_cxserver_reader = cxserver_reader.clone();
-            if let Some(_cxserver_config) = &server.connection_servers {
-                if let Some(ref cache) = local_cxserver_reader.get_one(&server.name){
-                    let names = cache.0.clone();
-                    log::debug!("Server:{} has connection servers:{:?}", server.name, names);
-                    for name in names {
-                        let test_server = server.clone();
-                        let test_sender = sender.clone();
-                        let metrics = cache.1.clone();
-                        let task = tokio::spawn(async move {
-                            match repeated_connection_server_query(&test_server, &name, metrics, test_sender,query_timeout).await {
-                                Ok(_) => {
-                                    // log::info!("connection server query finished smoothly");
-                                }
-                                Err(e) => {
-                                    log::error!("connection server query error:{:?}", e);
-                                }
-                            }
-                        });
-                        tasks.push(task);
-                    }
-                }
-            }
-
-            // c3p0 query
-            let local_jmx_reader = jmx_reader.clone();
-            if let Some(_jmx_config) = &server.jmx_metrics {
-                if let Some(ref cache) = local_jmx_reader.get_one(&server.name){
-                    // let jmx_metrics_vec = cache.clone();
-                    log::debug!("Server:{} has jmx metrics:{:?}", server.name, cache);
-                    for (measurement, object_name_list, name_alternative, metrics) in cache.iter() {
-                        let test_server = server.clone();
-                        let test_sender = sender.clone();
-                        let measurement = measurement.clone();
-                        let object_name_list = object_name_list.clone();
-                        let name_alternative = name_alternative.clone();
-                        let metrics = metrics.clone();
-                        let task = tokio::spawn(async move {
-                            match crate::jmxquery::repeated_jmx_query(&test_server,
-                                 measurement,
-                                 object_name_list,
-                                 name_alternative,
-                                 metrics, 
-                                 test_sender,query_timeout).await {
-                                Ok(_) => {
-                                    // log::info!("connection server query finished smoothly");
-                                }
-                                Err(e) => {
-                                    log::error!("JMX query error:{:?}", e);
-                                }
-                            }
-                        });
-                        tasks.push(task);
-                    }
-                }
-            }
-
-            // arbitrary url metrics query
-            if let Some(ref arbitrary_config) = &server.arbitrary_metrics {
-                for am in arbitrary_config.iter() {
-                    if !am.enabled {
-                        continue;
-                    }
-                    let test_server = server.clone();
-                    let test_sender = sender.clone();
-                    let am = am.clone();
-                    let task = tokio::spawn(async move {
-                        match repeated_arbitrary_query(&test_server, am, test_sender,query_timeout).await {
-                            Ok(_) => {
-                                // log::info!("connection server query finished smoothly");
-                            }
-                            Err(e) => {
-                                log::error!("arbitrary url query error:{:?}", e);
-                            }
-                        }
-                    });
-                    tasks.push(task);
-                }
-            }
-        }
-        for task in tasks {
-            task.await?;
-        }
-        if !running.load(Ordering::SeqCst) {
-            break;
-        }
-        let spent_time = SystemTime::now().duration_since(start_time).unwrap();
-        
-        if scrap_interval * 1000 > spent_time.as_millis() as u64 {
-            let sleep_time = scrap_interval * 1000 - spent_time.as_millis() as u64;
-            log::info!("sleep {} seconds", sleep_time/1000);
-            sleeping.store(true, Ordering::SeqCst);
-            tokio::time::sleep(std::time::Duration::from_millis(sleep_time)).await;
-            sleeping.store(false, Ordering::SeqCst);
-        }
+        let tc = config.borrow_and_update().clone();
+        scheduler.reconcile(&tc);
+        COLLECTOR_QUEUE_DEPTH.set((sender.max_capacity() - sender.capacity()) as i64);
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
     }
-    
+    scheduler.stop().await;
+    cxserver_task.abort();
+    jmx_task.abort();
+
     Ok(())
 }
 
@@ -199,30 +65,26 @@ pub async fn repeated_arbitrary_query(
     query_timeout: u64,
 )->anyhow::Result<()>{
     let url = server.get_arbitrary_access_url(&am.url);
-    let client = reqwest::Client::builder()
-    .timeout(std::time::Duration::from_secs(query_timeout))
-        .danger_accept_invalid_certs(true)
-        .build()?;
+    let client = client_for(server, query_timeout)?;
     log::debug!("Arbitrary metrics query service url:{}", url);
-    let headers = construct_headers(&server.app_key);
     let metrics_name = am.name.clone();
     let am_subsystem = SubSystem{
         name: am.name,
         options: am.options,
         enabled: am.enabled,
         sanitize: am.sanitize,
-        split_desc_asprefix: am.split_desc_asprefix
+        split_desc_asprefix: am.split_desc_asprefix,
+        schedule: am.schedule,
     };
 
-    let result = match query_subsystem_metrics(client, &url, &headers, &am_subsystem,&server.name,None).await{
-        Ok(result) => result,
-        Err(e) => {
-            log::error!("Arbitrary metrics query service error:{:?}", e);
-            return Ok(());
+    run_query(server, "arbitrary", &metrics_name, &sender, |mut scrape, headers| {
+        let client = client.clone();
+        let (url, am_subsystem) = (&url, &am_subsystem);
+        async move {
+            let result = query_subsystem_metrics(client, url, &headers, am_subsystem,&server.name,None,&mut scrape).await;
+            (scrape, result)
         }
-    };
-    log::debug!("Arbitrary metrics:{} metrics result:{}",metrics_name, result.len());
-    let _ = sender.send(result).await;
+    }).await;
 
     Ok(())
 }
@@ -234,59 +96,81 @@ pub async fn repeated_connection_server_query(
     query_timeout: u64,
 )->anyhow::Result<()>{
     let url = server.get_cxserver_query_service_url(cxserver_name);
-    let client = reqwest::Client::builder()
-        .timeout(std::time::Duration::from_secs(query_timeout))
-        // This is supported on crate feature native-tls only
-        // .danger_accept_invalid_hostnames(true)
-        .danger_accept_invalid_certs(true)
-        .build()?;
+    let client = client_for(server, query_timeout)?;
     log::debug!("Connection Server query service url:{}", url);
-    let headers = construct_headers(&server.app_key);
     let cx_subsystem = SubSystem{
         name: "ConnectionServer".to_string(),
         options: Some(metrics),
         enabled: true,
         sanitize: true,
         split_desc_asprefix: false,
+        schedule: Schedule::default(),
     };
     let mut additional_tags=HashMap::new();
     additional_tags.insert("cxserver".to_string(), cxserver_name.to_string());
 
-    let result = match query_subsystem_metrics(client, &url, &headers, &cx_subsystem,&server.name,Some(additional_tags)).await{
-        Ok(result) => result,
-        Err(e) => {
-            log::error!("query connection server metrics error:{:?}", e);
-            return Ok(());
+    run_query(server, "cxserver", cxserver_name, &sender, |mut scrape, headers| {
+        let client = client.clone();
+        let additional_tags = additional_tags.clone();
+        let (url, cx_subsystem) = (&url, &cx_subsystem);
+        async move {
+            let result = query_subsystem_metrics(client, url, &headers, cx_subsystem,&server.name,Some(additional_tags),&mut scrape).await;
+            (scrape, result)
         }
-    };
-    log::debug!("query connection server:{} metrics result:{}",cxserver_name, result.len());
-    let _ = sender.send(result).await;
+    }).await;
     Ok(())
 
 }
 
 pub async fn refresh_connection_server(
-    tc:TestConfig,
+    mut config: ConfigReceiver,
     // reader: &evmap::ReadHandle<String, (Vec<String>,Vec<String>)>,
     mut writer: evmap::WriteHandle<String, (Vec<String>,Vec<String>)>,
-    sleeping: Arc<AtomicBool>,
 )->anyhow::Result<()>{
-    
+    // the servers in the map, their entry is removed with their connection_servers block.
+    let mut written: HashSet<String> = HashSet::new();
     loop{
+        let tc = config.borrow_and_update().clone();
+        let mut configured = HashSet::new();
+        for server in tc.thingworx_servers.iter(){
+            if let Some(ref cxserver_config) = server.connection_servers{
+                configured.insert(server.name.clone());
+                if !cxserver_config.names.is_empty(){
+                    writer.update(server.name.clone(), (cxserver_config.names.clone(),cxserver_config.metrics.clone()));
+                }
+            }
+        }
+        for name in written.difference(&configured){
+            log::info!("connection servers of server:{} removed", name);
+            writer.empty(name.clone());
+        }
+        writer.refresh();
+        written = configured;
+
         for server in tc.thingworx_servers.iter(){
             if let Some(ref cxserver_config) = server.connection_servers{
                 // if it has a name list configured, then we ignore it.
                 if !cxserver_config.names.is_empty(){
                     continue;
                 }
-                let headers = construct_headers(&server.app_key);
                 let url = server.get_cxserver_query_url();
-                let client = reqwest::Client::builder()
-                    .timeout(std::time::Duration::from_secs(20))
-                    .danger_accept_invalid_certs(true)
-                    .build()?;
+                let client = match client_for(server, tc.query_time_out){
+                    Ok(client) => client,
+                    Err(e) => {
+                        log::error!("connection server query service error:{:?}", e);
+                        continue;
+                    }
+                };
+                let headers = match headers_for(server).await{
+                    Ok(headers) => headers,
+                    Err(e) => {
+                        log::error!("connection server query service error:{:?}", e);
+                        continue;
+                    }
+                };
                 log::debug!("connection server query service url:{}", url);
-                let res = match client.post(url).headers(headers.clone()).send().await{
+                let _permit = acquire(&server.name).await;
+                let res = match client.post(url).headers(headers.clone()).timeout(std::time::Duration::from_secs(20)).send().await{
                     Ok(res) => res,
                     Err(e) => {
                         log::error!("connection server query service error:{:?}", e);
@@ -295,6 +179,9 @@ pub async fn refresh_connection_server(
                 };
                 if !res.status().is_success() {
                     log::error!("connection server query :{} failed", server.name);
+                    if res.status() == StatusCode::UNAUTHORIZED {
+                        invalidate(server, &headers).await;
+                    }
                     continue;
                 }
             
@@ -319,45 +206,31 @@ pub async fn refresh_connection_server(
             }
         }
         writer.refresh();
-        sleeping.store(true, Ordering::SeqCst);
-        tokio::time::sleep(std::time::Duration::from_secs(tc.refresh_server_interval )).await;
-        sleeping.store(false, Ordering::SeqCst);
+        sleep_or_reload(&mut config, std::time::Duration::from_secs(tc.refresh_server_interval)).await;
     }
     // Ok(())
 }
-pub async fn repeated_twxserver_query(
+pub async fn repeated_subsystem_query(
     server: &ThingworxServer,
+    subsystem: &SubSystem,
     sender: Sender<Vec<WriteQuery>>,
     query_timeout:u64,
 ) -> anyhow::Result<()> {
-    let url = format!("{}://{}:{}", server.protocol, server.host, server.port);
-    let client = reqwest::Client::builder()
-        .timeout(std::time::Duration::from_secs(query_timeout))
-        // This is supported on crate feature native-tls only
-        // .danger_accept_invalid_hostnames(true)
-        .danger_accept_invalid_certs(true)
-        .build()?;
+    let url = format!("{}://{}:{}", server.protocol, server.url_host(), server.port);
+    let client = client_for(server, query_timeout)?;
     log::debug!("twxquery service url:{}", url);
-    let headers = construct_headers(&server.app_key);
-    for subsystem in server.subsystems.iter() {
-        if !subsystem.enabled {
-            continue;
+    let sys_url = format!(
+        "{}/{}/Subsystems/{}/Services/GetPerformanceMetrics",
+        url, server.application, subsystem.name
+    );
+    run_query(server, "subsystem", &subsystem.name, &sender, |mut scrape, headers| {
+        let client = client.clone();
+        let sys_url = &sys_url;
+        async move {
+            let result = query_subsystem_metrics(client, sys_url, &headers, subsystem, &server.name,None,&mut scrape).await;
+            (scrape, result)
         }
-        let sys_url = format!(
-            "{}/{}/Subsystems/{}/Services/GetPerformanceMetrics",
-            url, server.application, subsystem.name
-        );
-        match query_subsystem_metrics(client.clone(), &sys_url, &headers, subsystem, &server.name,None).await {
-            Ok(metrics) => {
-                log::debug!("result from subsystem:{} has:{} metrics", subsystem.name, metrics.len());
-                let _ = sender.send(metrics).await;
-            }
-            Err(e) => {
-                log::error!("query subsystem metrics error:{:?}", e);
-                break;
-            }
-        }
-    }
+    }).await;
     Ok(())
 }
 
@@ -384,14 +257,18 @@ pub async fn query_subsystem_metrics(
     subsystem: &SubSystem,
     platform:&str,
     additional_tags:Option<HashMap<String,String>>,
+    scrape: &mut Scrape,
 ) -> anyhow::Result<Vec<WriteQuery>> {
     let mut result = vec![];
+    let _permit = acquire(platform).await;
+    scrape.restart();
     let response_start = SystemTime::now();
     
     // if this step is error, likely the server is not responsive.
     // so we should not continue to query the metrics.
     // for the rest of the metrics, we will just handle the error within this block.
     let res = client.post(url).headers(headers.clone()).send().await?;
+    scrape.response(res.status());
     if !res.status().is_success() {
         // return Err(anyhow::anyhow!("Subsystem metrics query:{} failed", url));
         log::error!("Subsystem metrics query:{} failed", url);
@@ -406,6 +283,7 @@ pub async fn query_subsystem_metrics(
                 url,
                 e
             );
+            scrape.parse_error();
             return Ok(result); // return empty result
         }
     };